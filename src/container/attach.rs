@@ -0,0 +1,1065 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::{self, FromStr};
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::sys::stat::Mode;
+use nix::unistd::{chown, Gid, Uid};
+use serde::{Deserialize, Serialize};
+
+use super::exit::ExitStatus;
+
+const BUF_SIZE: usize = 32 * 1024;
+
+// Attach protocol v1. Every frame is a 1-byte type followed by a 4-byte
+// big-endian payload length and the payload itself:
+//
+//   HELLO     (0, both ways)       JSON object, e.g. {"version": 1}
+//   STDOUT    (1, shim -> client)  container's output
+//   STDERR    (2, shim -> client)  container's output
+//   STDIN     (3, client -> shim)  container's input
+//   STDIN_EOF (4, client -> shim)  no more input from this client
+//   RESIZE    (5, client -> shim)  u16 rows, u16 cols (big-endian)
+//   EXIT      (6, shim -> client)  JSON exit status, the last frame sent
+//
+// A client opts in by sending HELLO as the very first frame and gets HELLO
+// back. Client's HELLO may also carry attach options:
+//
+//   "replay": <bytes> | "all"   output history to send before live output
+//   "streams": ["stdout", ...]  output streams to receive, both by default
+//   "readonly": true            STDIN, STDIN_EOF, and RESIZE frames are ignored
+//   "takeover": true            become the STDIN owner (--stdin-exclusive mode)
+//
+// Replayed history is the output as of the client's connect, up to the
+// requested number of bytes per stream. History is kept per stream, so
+// stdout history is sent before stderr history whatever their original
+// order was.
+//
+// Clients that don't send HELLO keep using the raw protocol: bytes in
+// are forwarded to the container's STDIN as is (but for resize sequences
+// in terminal mode, see RESIZE_PREFIX), and chunks out are prefixed
+// with a single stream byte (STDOUT, STDERR, or EXIT, same values as frame
+// types). Since EXIT is followed by the socket shutdown, raw clients can
+// read its JSON payload till EOF.
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(100);
+
+const FRAME_HELLO: u8 = 0;
+const FRAME_STDOUT: u8 = 1;
+const FRAME_STDERR: u8 = 2;
+const FRAME_STDIN: u8 = 3;
+const FRAME_STDIN_EOF: u8 = 4;
+const FRAME_RESIZE: u8 = 5;
+const FRAME_EXIT: u8 = 6;
+
+const FRAME_HEADER_SIZE: usize = 5;
+const FRAME_MAX_PAYLOAD_SIZE: usize = 1024 * 1024;
+const HELLO_MAX_PAYLOAD_SIZE: usize = 4 * 1024;
+
+// Output produced before the protocol is known is held back up to this size.
+const BACKLOG_MAX_SIZE: usize = 1024 * 1024;
+
+// Raw clients request PTY resizes in-band, using the xterm
+// "resize text area" control sequence: ESC [ 8 ; <rows> ; <cols> t
+const RESIZE_PREFIX: &[u8] = b"\x1b[8;";
+const RESIZE_MAX_LEN: usize = 16; // ESC [ 8 ; 65535 ; 65535 t
+
+#[derive(Deserialize, Serialize)]
+pub struct Hello {
+    pub version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay: Option<ReplaySpec>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub streams: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub readonly: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub takeover: bool,
+}
+
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum ReplaySpec {
+    Bytes(usize),
+    Name(String),
+}
+
+impl ReplaySpec {
+    // Accepts a number of bytes or "all".
+    pub fn to_limit(&self) -> Option<usize> {
+        match self {
+            Self::Bytes(limit) => Some(*limit),
+            Self::Name(name) if name == "all" => Some(usize::MAX),
+            Self::Name(_) => None,
+        }
+    }
+}
+
+// What to do with a client whose unsent output exceeds the queue size.
+#[derive(Copy, Clone, Debug)]
+pub enum OverflowPolicy {
+    DropOldest,
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(format!("unknown overflow policy {}", s)),
+        }
+    }
+}
+
+// Docker-style key sequence that detaches an attach client
+// without closing container's STDIN, e.g. "ctrl-p,ctrl-q".
+#[derive(Clone, Debug)]
+pub struct DetachKeys(Vec<u8>);
+
+impl FromStr for DetachKeys {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut keys = Vec::new();
+        for key in s.split(',').map(|k| k.trim()) {
+            let code = match key.strip_prefix("ctrl-") {
+                Some(name) if name.len() == 1 => match name.as_bytes()[0] {
+                    c @ b'a'..=b'z' => c - b'a' + 1,
+                    b'@' => 0,
+                    c @ b'['..=b'_' => c - b'@',
+                    _ => return Err(format!("unknown detach key {}", key)),
+                },
+                None if key.len() == 1 && key.is_ascii() => key.as_bytes()[0],
+                _ => return Err(format!("unknown detach key {}", key)),
+            };
+            keys.push(code);
+        }
+        Ok(Self(keys))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub queue_size: usize,
+    pub overflow_policy: OverflowPolicy,
+    // Raw clients' input is scanned for resize sequences only if there is
+    // a console to resize, otherwise it's forwarded untouched.
+    pub raw_resize: bool,
+    pub detach_keys: Option<DetachKeys>,
+}
+
+// Who may use the attach socket. The file mode and ownership restrict
+// who can connect at all, the allowed lists are checked against the peer
+// credentials on accept(). With both lists empty any peer is accepted.
+#[derive(Clone, Debug, Default)]
+pub struct Access {
+    pub socket_mode: Option<u32>,
+    pub socket_uid: Option<u32>,
+    pub socket_gid: Option<u32>,
+    pub allowed_uids: Vec<u32>,
+    pub allowed_gids: Vec<u32>,
+}
+
+impl Access {
+    // The socket is expected to be bound owner-only; without an explicit
+    // socket_mode it gets the mode the process umask would have given it.
+    pub fn apply<P: AsRef<Path>>(&self, socket_path: P, umask: Mode) -> io::Result<()> {
+        if self.socket_uid.is_some() || self.socket_gid.is_some() {
+            chown(
+                socket_path.as_ref(),
+                self.socket_uid.map(Uid::from_raw),
+                self.socket_gid.map(Gid::from_raw),
+            )?;
+        }
+        let mode = self.socket_mode.unwrap_or(0o777 & !umask.bits());
+        fs::set_permissions(socket_path, fs::Permissions::from_mode(mode))
+    }
+
+    // SO_PEERCRED carries only the primary group of the peer.
+    pub fn authorize(&self, stream: &UnixStream) -> Result<(), String> {
+        if self.allowed_uids.is_empty() && self.allowed_gids.is_empty() {
+            return Ok(());
+        }
+
+        let creds = getsockopt(stream.as_raw_fd(), PeerCredentials)
+            .map_err(|err| format!("getsockopt(SO_PEERCRED) failed: {}", err))?;
+        if self.allowed_uids.contains(&creds.uid()) || self.allowed_gids.contains(&creds.gid()) {
+            Ok(())
+        } else {
+            Err(format!(
+                "peer pid={} uid={} gid={} is not allowed",
+                creds.pid(),
+                creds.uid(),
+                creds.gid()
+            ))
+        }
+    }
+}
+
+pub enum Message {
+    Hello(Hello),
+    Data(Vec<u8>),
+    Resize { rows: u16, cols: u16 },
+    StdinEof,
+    // The client typed the detach keys, it's always the last message.
+    Detach,
+    Eof,
+}
+
+enum Protocol {
+    Handshake(Instant),
+    Raw,
+    Framed,
+}
+
+enum Handshake {
+    Pending,
+    Raw,
+    Framed(Hello),
+}
+
+pub struct Stream {
+    inner: UnixStream,
+    config: Config,
+    protocol: Protocol,
+    pending: Vec<u8>,
+    // Output history as of accept() and output since then,
+    // held back until the handshake is over.
+    history: Vec<Vec<u8>>,
+    backlog: Vec<Vec<u8>>,
+    backlog_size: usize,
+    outbox: VecDeque<Vec<u8>>,
+    outbox_size: usize,
+    outbox_offset: usize,
+    stdout: bool,
+    stderr: bool,
+    readonly: bool,
+    stdin_closed: bool,
+    // How many leading detach keys the client has typed so far.
+    detach_matched: usize,
+    eof: bool,
+    closed: bool,
+}
+
+impl Stream {
+    pub fn new(inner: UnixStream, config: Config, history: Vec<Vec<u8>>) -> Self {
+        inner
+            .set_nonblocking(true)
+            .expect("Couldn't set attach stream nonblocking");
+
+        Self {
+            inner,
+            config,
+            protocol: Protocol::Handshake(Instant::now()),
+            pending: Vec::new(),
+            history,
+            backlog: Vec::new(),
+            backlog_size: 0,
+            outbox: VecDeque::new(),
+            outbox_size: 0,
+            outbox_offset: 0,
+            stdout: true,
+            stderr: true,
+            readonly: false,
+            stdin_closed: false,
+            detach_matched: 0,
+            eof: false,
+            closed: false,
+        }
+    }
+
+    pub fn wants_read(&self) -> bool {
+        !self.eof && !self.closed
+    }
+
+    pub fn wants_write(&self) -> bool {
+        !self.outbox.is_empty() && !self.closed
+    }
+
+    // Writes as much of the queued output as the socket takes without blocking.
+    pub fn flush_outbox(&mut self) -> io::Result<()> {
+        while let Some(chunk) = self.outbox.front() {
+            let len = chunk.len();
+            match self.inner.write(&chunk[self.outbox_offset..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(nwritten) => {
+                    self.outbox_offset += nwritten;
+                    if self.outbox_offset == len {
+                        self.outbox_size -= len;
+                        self.outbox_offset = 0;
+                        self.outbox.pop_front();
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_handshaking(&self) -> bool {
+        matches!(self.protocol, Protocol::Handshake(..))
+    }
+
+    // Detaching is one-sided: the client gets no more output, not even EXIT.
+    pub fn detach(&mut self) {
+        self.closed = true;
+        if let Err(err) = self.inner.shutdown(Shutdown::Both) {
+            debug!("[shim] attach stream shutdown failed: {}", err);
+        }
+    }
+
+    // Read-only clients never affect container's STDIN, including its closing.
+    pub fn is_readonly(&self) -> bool {
+        self.readonly
+    }
+
+    // Set once writing to the client has failed (or it fell behind).
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    // Queues EXIT, the last message to the client, see flush_exit().
+    pub fn send_exit(&mut self, status: &ExitStatus) -> io::Result<()> {
+        if self.is_handshaking() {
+            self.start_raw()?;
+        }
+
+        let mut payload = status.to_json();
+        let message = match self.protocol {
+            Protocol::Framed => frame(FRAME_EXIT, &payload),
+            _ => {
+                payload.insert(0, FRAME_EXIT);
+                payload
+            }
+        };
+        self.outbox_size += message.len();
+        self.outbox.push_back(message);
+        self.flush_outbox()
+    }
+
+    // Returns true once the whole output including EXIT is sent
+    // and the stream is shut down for writing.
+    pub fn flush_exit(&mut self) -> io::Result<bool> {
+        self.flush_outbox()?;
+        if !self.outbox.is_empty() {
+            return Ok(false);
+        }
+        self.inner.shutdown(Shutdown::Write)?;
+        Ok(true)
+    }
+
+    // Clients that keep silence for too long are considered raw.
+    pub fn expire_handshake(&mut self) -> io::Result<()> {
+        match self.protocol {
+            Protocol::Handshake(started_at) if started_at.elapsed() >= HANDSHAKE_TIMEOUT => {
+                self.start_raw()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn receive(&mut self) -> io::Result<Vec<Message>> {
+        let messages = self.decode()?;
+        Ok(self.detect_detach(messages))
+    }
+
+    fn decode(&mut self) -> io::Result<Vec<Message>> {
+        let mut buf = [0; BUF_SIZE];
+        let nread = match self.inner.read(&mut buf) {
+            Ok(nread) => nread,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        if nread == 0 {
+            return self.receive_eof();
+        }
+
+        self.pending.extend_from_slice(&buf[..nread]);
+
+        let mut messages = Vec::new();
+        if self.is_handshaking() {
+            match self.handshake() {
+                Handshake::Pending => return Ok(messages),
+                Handshake::Raw => self.start_raw()?,
+                Handshake::Framed(hello) => {
+                    self.start_framed(&hello)?;
+                    messages.push(Message::Hello(hello));
+                }
+            }
+        }
+
+        match self.protocol {
+            Protocol::Framed => messages.extend(self.decode_frames()?),
+            _ => messages.extend(self.decode_raw()),
+        }
+        Ok(messages)
+    }
+
+    // Must follow Message::Hello. Flushes output held back during the
+    // handshake, preceded by up to `replay` bytes of each stream's history.
+    pub fn start(&mut self, replay: Option<usize>) -> io::Result<()> {
+        let history = std::mem::take(&mut self.history);
+        if let Some(limit) = replay {
+            for chunk in history.iter() {
+                let skip = (chunk.len() - 1).saturating_sub(limit);
+                if chunk.len() - skip > 1 {
+                    let mut trimmed = Vec::with_capacity(chunk.len() - skip);
+                    trimmed.push(chunk[0]);
+                    trimmed.extend_from_slice(&chunk[1 + skip..]);
+                    self.write_chunk(&trimmed)?;
+                }
+            }
+        }
+
+        for chunk in self.backlog.split_off(0) {
+            self.write_chunk(&chunk)?;
+        }
+        self.backlog_size = 0;
+        Ok(())
+    }
+
+    fn receive_eof(&mut self) -> io::Result<Vec<Message>> {
+        self.eof = true;
+        if self.is_handshaking() {
+            self.start_raw()?;
+        }
+
+        let mut messages = Vec::new();
+        if let Protocol::Raw = self.protocol {
+            if !self.pending.is_empty() {
+                messages.push(Message::Data(self.pending.split_off(0)));
+            }
+        }
+        messages.push(Message::Eof);
+        Ok(messages)
+    }
+
+    // Detach keys are matched across reads. Keys typed so far are held back
+    // until the sequence is either completed (then they are dropped along
+    // with anything that follows) or broken (then they are forwarded).
+    fn detect_detach(&mut self, messages: Vec<Message>) -> Vec<Message> {
+        let keys = match self.config.detach_keys.as_ref() {
+            Some(DetachKeys(keys)) => keys,
+            None => return messages,
+        };
+
+        let mut detected = Vec::with_capacity(messages.len());
+        for message in messages {
+            let data = match message {
+                Message::Data(data) => data,
+                message => {
+                    detected.push(message);
+                    continue;
+                }
+            };
+
+            let mut forwarded = Vec::with_capacity(self.detach_matched + data.len());
+            for c in data {
+                if c == keys[self.detach_matched] {
+                    self.detach_matched += 1;
+                    if self.detach_matched < keys.len() {
+                        continue;
+                    }
+                    if !forwarded.is_empty() {
+                        detected.push(Message::Data(forwarded));
+                    }
+                    detected.push(Message::Detach);
+                    return detected;
+                }
+
+                // What's still held back is the longest tail of the typed keys
+                // that starts the sequence anew (e.g. "aa" of "aaa" for "a,a,b").
+                let mut typed = keys[..self.detach_matched].to_vec();
+                typed.push(c);
+                let held = (0..typed.len())
+                    .rev()
+                    .find(|n| typed.ends_with(&keys[..*n]))
+                    .unwrap_or(0);
+                forwarded.extend_from_slice(&typed[..typed.len() - held]);
+                self.detach_matched = held;
+            }
+            if !forwarded.is_empty() {
+                detected.push(Message::Data(forwarded));
+            }
+        }
+        detected
+    }
+
+    // Pending while the client's first bytes may still turn into HELLO.
+    fn handshake(&mut self) -> Handshake {
+        if self.pending[0] != FRAME_HELLO {
+            return Handshake::Raw;
+        }
+        if self.pending.len() < FRAME_HEADER_SIZE {
+            return Handshake::Pending;
+        }
+
+        let len = payload_size(&self.pending);
+        if len > HELLO_MAX_PAYLOAD_SIZE {
+            return Handshake::Raw;
+        }
+        if self.pending.len() < FRAME_HEADER_SIZE + len {
+            return Handshake::Pending;
+        }
+
+        let payload = &self.pending[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len];
+        match serde_json::from_slice::<Hello>(payload) {
+            Ok(hello) => {
+                debug!("[shim] attach client speaks protocol v{}", hello.version);
+                self.pending.drain(..FRAME_HEADER_SIZE + len);
+                Handshake::Framed(hello)
+            }
+            Err(_) => Handshake::Raw,
+        }
+    }
+
+    fn start_raw(&mut self) -> io::Result<()> {
+        self.protocol = Protocol::Raw;
+        self.history = Vec::new();
+        for chunk in self.backlog.split_off(0) {
+            self.enqueue(chunk)?;
+        }
+        self.backlog_size = 0;
+        Ok(())
+    }
+
+    fn start_framed(&mut self, options: &Hello) -> io::Result<()> {
+        self.protocol = Protocol::Framed;
+        self.readonly = options.readonly;
+        if let Some(streams) = options.streams.as_ref() {
+            self.stdout = false;
+            self.stderr = false;
+            for name in streams {
+                match name.as_str() {
+                    "stdout" => self.stdout = true,
+                    "stderr" => self.stderr = true,
+                    _ => warn!("[shim] attach client requested unknown stream {}", name),
+                }
+            }
+        }
+
+        let hello = Hello {
+            version: PROTOCOL_VERSION,
+            replay: None,
+            streams: None,
+            readonly: false,
+            takeover: false,
+        };
+        let payload = serde_json::to_vec(&hello).expect("JSON serialization failed");
+        self.enqueue(frame(FRAME_HELLO, &payload))
+    }
+
+    fn enqueue(&mut self, message: Vec<u8>) -> io::Result<()> {
+        self.outbox_size += message.len();
+        self.outbox.push_back(message);
+        self.flush_outbox()?;
+
+        if self.outbox_size > self.config.queue_size {
+            match self.config.overflow_policy {
+                OverflowPolicy::Disconnect => {
+                    return Err(io::Error::other("attach client falls behind"))
+                }
+                OverflowPolicy::DropOldest => self.drop_oldest(),
+            }
+        }
+        Ok(())
+    }
+
+    // Drops whole messages only, so framing stays intact.
+    fn drop_oldest(&mut self) {
+        // The head message may have been partially sent already.
+        let keep_head = if self.outbox_offset > 0 { 1 } else { 0 };
+
+        let mut ndropped = 0;
+        while self.outbox_size > self.config.queue_size && self.outbox.len() > keep_head {
+            let message = self.outbox.remove(keep_head).unwrap();
+            self.outbox_size -= message.len();
+            ndropped += message.len();
+        }
+        warn!(
+            "[shim] attach client falls behind, dropped {} byte(s) of output",
+            ndropped
+        );
+    }
+
+    fn decode_frames(&mut self) -> io::Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        let mut pos = 0;
+
+        while self.pending.len() - pos >= FRAME_HEADER_SIZE {
+            let len = payload_size(&self.pending[pos..]);
+            if len > FRAME_MAX_PAYLOAD_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "attach frame is too large",
+                ));
+            }
+            if self.pending.len() - pos < FRAME_HEADER_SIZE + len {
+                break;
+            }
+
+            let kind = self.pending[pos];
+            let payload = &self.pending[pos + FRAME_HEADER_SIZE..pos + FRAME_HEADER_SIZE + len];
+            match kind {
+                FRAME_STDIN | FRAME_STDIN_EOF | FRAME_RESIZE if self.readonly => {
+                    debug!(
+                        "[shim] ignoring attach frame of type {} from read-only client",
+                        kind
+                    )
+                }
+                FRAME_STDIN if !self.stdin_closed && !payload.is_empty() => {
+                    messages.push(Message::Data(payload.to_vec()))
+                }
+                FRAME_STDIN_EOF if !self.stdin_closed => {
+                    self.stdin_closed = true;
+                    messages.push(Message::StdinEof);
+                }
+                FRAME_RESIZE if payload.len() == 4 => messages.push(Message::Resize {
+                    rows: u16::from_be_bytes([payload[0], payload[1]]),
+                    cols: u16::from_be_bytes([payload[2], payload[3]]),
+                }),
+                _ => debug!("[shim] ignoring attach frame of type {}", kind),
+            }
+            pos += FRAME_HEADER_SIZE + len;
+        }
+
+        self.pending.drain(..pos);
+        Ok(messages)
+    }
+
+    fn decode_raw(&mut self) -> Vec<Message> {
+        if !self.config.raw_resize {
+            return vec![Message::Data(self.pending.split_off(0))];
+        }
+
+        let mut messages = Vec::new();
+        let mut data_start = 0;
+        let mut pos = 0;
+
+        while let Some(offset) = self.pending[pos..].iter().position(|c| *c == 0x1b) {
+            pos += offset;
+            match parse_resize(&self.pending[pos..]) {
+                Parsed::Resize { rows, cols, len } => {
+                    if data_start < pos {
+                        messages.push(Message::Data(self.pending[data_start..pos].to_vec()));
+                    }
+                    messages.push(Message::Resize { rows, cols });
+                    pos += len;
+                    data_start = pos;
+                }
+                Parsed::Incomplete => {
+                    // Keep the sequence head until the next read completes it.
+                    if data_start < pos {
+                        messages.push(Message::Data(self.pending[data_start..pos].to_vec()));
+                    }
+                    self.pending.drain(..pos);
+                    return messages;
+                }
+                Parsed::Mismatch => pos += 1,
+            }
+        }
+
+        if data_start < self.pending.len() {
+            messages.push(Message::Data(self.pending[data_start..].to_vec()));
+        }
+        self.pending.clear();
+        messages
+    }
+
+    fn write_chunk(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let subscribed = match buf[0] {
+            FRAME_STDOUT => self.stdout,
+            FRAME_STDERR => self.stderr,
+            _ => true,
+        };
+        if !subscribed {
+            return Ok(buf.len());
+        }
+
+        match self.protocol {
+            Protocol::Handshake(..) => {
+                self.backlog.push(buf.to_vec());
+                self.backlog_size += buf.len();
+                if self.backlog_size > BACKLOG_MAX_SIZE {
+                    self.start_raw()?;
+                }
+            }
+            Protocol::Raw => self.enqueue(buf.to_vec())?,
+            Protocol::Framed => {
+                debug_assert!(buf[0] == FRAME_STDOUT || buf[0] == FRAME_STDERR);
+                self.enqueue(frame(buf[0], &buf[1..]))?
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+// Expects chunks prefixed with the stream byte, i.e. the Scatterer's output.
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        let res = self.write_chunk(buf);
+        if res.is_err() {
+            self.closed = true;
+        }
+        res
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_outbox()
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn payload_size(header: &[u8]) -> usize {
+    u32::from_be_bytes([header[1], header[2], header[3], header[4]]) as usize
+}
+
+enum Parsed {
+    Resize { rows: u16, cols: u16, len: usize },
+    Incomplete,
+    Mismatch,
+}
+
+// A lone ESC (or any other sequence not starting with the full prefix)
+// is never held back, so interactive keystrokes aren't delayed.
+fn parse_resize(buf: &[u8]) -> Parsed {
+    if !buf.starts_with(RESIZE_PREFIX) {
+        return Parsed::Mismatch;
+    }
+
+    let body = &buf[RESIZE_PREFIX.len()..];
+    match body.iter().position(|c| *c == b't') {
+        Some(end) if RESIZE_PREFIX.len() + end < RESIZE_MAX_LEN => {
+            let mut dims = str::from_utf8(&body[..end])
+                .unwrap_or("")
+                .splitn(2, ';')
+                .map(|d| d.parse::<u16>());
+            match (dims.next(), dims.next()) {
+                (Some(Ok(rows)), Some(Ok(cols))) => Parsed::Resize {
+                    rows,
+                    cols,
+                    len: RESIZE_PREFIX.len() + end + 1,
+                },
+                _ => Parsed::Mismatch,
+            }
+        }
+        None if buf.len() < RESIZE_MAX_LEN
+            && body.iter().all(|c| c.is_ascii_digit() || *c == b';') =>
+        {
+            Parsed::Incomplete
+        }
+        _ => Parsed::Mismatch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    enum Msg {
+        Hello(u32),
+        Data(Vec<u8>),
+        Resize(u16, u16),
+        StdinEof,
+        Detach,
+        Eof,
+    }
+
+    fn pair(raw_resize: bool) -> (Stream, UnixStream) {
+        pair_with(raw_resize, None)
+    }
+
+    fn pair_with(raw_resize: bool, detach_keys: Option<&str>) -> (Stream, UnixStream) {
+        let (shim, client) = UnixStream::pair().unwrap();
+        let config = Config {
+            queue_size: 1024 * 1024,
+            overflow_policy: OverflowPolicy::Disconnect,
+            raw_resize,
+            detach_keys: detach_keys.map(|keys| keys.parse().unwrap()),
+        };
+        (Stream::new(shim, config, Vec::new()), client)
+    }
+
+    fn receive(stream: &mut Stream) -> Vec<Msg> {
+        stream
+            .receive()
+            .unwrap()
+            .into_iter()
+            .map(|message| match message {
+                Message::Hello(hello) => Msg::Hello(hello.version),
+                Message::Data(data) => Msg::Data(data),
+                Message::Resize { rows, cols } => Msg::Resize(rows, cols),
+                Message::StdinEof => Msg::StdinEof,
+                Message::Detach => Msg::Detach,
+                Message::Eof => Msg::Eof,
+            })
+            .collect()
+    }
+
+    fn read_frame(client: &mut UnixStream) -> (u8, Vec<u8>) {
+        let mut header = [0; FRAME_HEADER_SIZE];
+        client.read_exact(&mut header).unwrap();
+        let mut payload = vec![0; payload_size(&header)];
+        client.read_exact(&mut payload).unwrap();
+        (header[0], payload)
+    }
+
+    #[test]
+    fn framed_protocol() {
+        let (mut stream, mut client) = pair(false);
+        client
+            .write_all(&frame(FRAME_HELLO, br#"{"version": 1}"#))
+            .unwrap();
+        client.write_all(&frame(FRAME_STDIN, b"hello")).unwrap();
+        assert_eq!(
+            receive(&mut stream),
+            vec![Msg::Hello(1), Msg::Data(b"hello".to_vec())]
+        );
+
+        let (kind, payload) = read_frame(&mut client);
+        assert_eq!(kind, FRAME_HELLO);
+        let hello: Hello = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(hello.version, PROTOCOL_VERSION);
+
+        // Frames may come split across reads.
+        let resize = frame(FRAME_RESIZE, &[0, 24, 0, 80]);
+        client.write_all(&resize[..3]).unwrap();
+        assert_eq!(receive(&mut stream), vec![]);
+        client.write_all(&resize[3..]).unwrap();
+        client.write_all(&frame(FRAME_STDIN_EOF, b"")).unwrap();
+        client.write_all(&frame(FRAME_STDIN, b"ignored")).unwrap();
+        assert_eq!(
+            receive(&mut stream),
+            vec![Msg::Resize(24, 80), Msg::StdinEof]
+        );
+
+        stream.write_all(&[FRAME_STDOUT, b'o', b'k']).unwrap();
+        assert_eq!(read_frame(&mut client), (FRAME_STDOUT, b"ok".to_vec()));
+    }
+
+    #[test]
+    fn replay_precedes_output_held_back_during_handshake() {
+        let (shim, mut client) = UnixStream::pair().unwrap();
+        let history = vec![b"\x01hello".to_vec(), b"\x02oops".to_vec()];
+        let config = Config {
+            queue_size: 1024 * 1024,
+            overflow_policy: OverflowPolicy::Disconnect,
+            raw_resize: false,
+            detach_keys: None,
+        };
+        let mut stream = Stream::new(shim, config, history);
+
+        stream.write_all(b"\x01live").unwrap();
+        client
+            .write_all(&frame(FRAME_HELLO, br#"{"version": 1, "replay": 3}"#))
+            .unwrap();
+        assert_eq!(receive(&mut stream), vec![Msg::Hello(1)]);
+        stream.start(Some(3)).unwrap();
+
+        assert_eq!(read_frame(&mut client).0, FRAME_HELLO);
+        assert_eq!(read_frame(&mut client), (FRAME_STDOUT, b"llo".to_vec()));
+        assert_eq!(read_frame(&mut client), (FRAME_STDERR, b"ops".to_vec()));
+        assert_eq!(read_frame(&mut client), (FRAME_STDOUT, b"live".to_vec()));
+    }
+
+    #[test]
+    fn framed_protocol_rejects_oversized_frames() {
+        let (mut stream, mut client) = pair(false);
+        client
+            .write_all(&frame(FRAME_HELLO, br#"{"version": 1}"#))
+            .unwrap();
+        receive(&mut stream);
+
+        let mut header = vec![FRAME_STDIN];
+        header.extend_from_slice(&(FRAME_MAX_PAYLOAD_SIZE as u32 + 1).to_be_bytes());
+        client.write_all(&header).unwrap();
+        assert!(stream.receive().is_err());
+    }
+
+    #[test]
+    fn readonly_clients_input_is_ignored() {
+        let (mut stream, mut client) = pair(false);
+        client
+            .write_all(&frame(FRAME_HELLO, br#"{"version": 1, "readonly": true}"#))
+            .unwrap();
+        client.write_all(&frame(FRAME_STDIN, b"hello")).unwrap();
+        client
+            .write_all(&frame(FRAME_RESIZE, &[0, 24, 0, 80]))
+            .unwrap();
+        client.write_all(&frame(FRAME_STDIN_EOF, b"")).unwrap();
+        assert_eq!(receive(&mut stream), vec![Msg::Hello(1)]);
+        assert!(stream.is_readonly());
+    }
+
+    #[test]
+    fn raw_protocol_resize_sequences() {
+        let (mut stream, mut client) = pair(true);
+        client.write_all(b"ab\x1b[8;24;80tcd\x1b[8;2").unwrap();
+        assert_eq!(
+            receive(&mut stream),
+            vec![
+                Msg::Data(b"ab".to_vec()),
+                Msg::Resize(24, 80),
+                Msg::Data(b"cd".to_vec()),
+            ]
+        );
+
+        // The incomplete sequence is held back until the next read.
+        client.write_all(b"5;100t\x1b[Ae").unwrap();
+        assert_eq!(
+            receive(&mut stream),
+            vec![Msg::Resize(25, 100), Msg::Data(b"\x1b[Ae".to_vec())]
+        );
+
+        client.write_all(b"\x1b[8;").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(receive(&mut stream), vec![]);
+        assert_eq!(
+            receive(&mut stream),
+            vec![Msg::Data(b"\x1b[8;".to_vec()), Msg::Eof]
+        );
+    }
+
+    #[test]
+    fn raw_protocol_without_console() {
+        let (mut stream, mut client) = pair(false);
+        client.write_all(b"ab\x1b[8;24;80tcd").unwrap();
+        assert_eq!(
+            receive(&mut stream),
+            vec![Msg::Data(b"ab\x1b[8;24;80tcd".to_vec())]
+        );
+    }
+
+    #[test]
+    fn parse_resize_sequences() {
+        assert!(matches!(
+            parse_resize(b"\x1b[8;65535;65535t"),
+            Parsed::Resize {
+                rows: 65535,
+                cols: 65535,
+                len: 16
+            }
+        ));
+        assert!(matches!(parse_resize(b"\x1b[8;24;80"), Parsed::Incomplete));
+        assert!(matches!(parse_resize(b"\x1b[8"), Parsed::Mismatch));
+        assert!(matches!(parse_resize(b"\x1b"), Parsed::Mismatch));
+        assert!(matches!(parse_resize(b"\x1b[8;24;x"), Parsed::Mismatch));
+        assert!(matches!(
+            parse_resize(b"\x1b[8;65536;80t"),
+            Parsed::Mismatch
+        ));
+        assert!(matches!(parse_resize(b"\x1b[8;1;2;3t"), Parsed::Mismatch));
+        assert!(matches!(
+            parse_resize(b"\x1b[8;123456;123456"),
+            Parsed::Mismatch
+        ));
+    }
+
+    #[test]
+    fn parse_detach_keys() {
+        let keys: DetachKeys = "ctrl-p,ctrl-q".parse().unwrap();
+        assert_eq!(keys.0, vec![0x10, 0x11]);
+        let keys: DetachKeys = "ctrl-@, ctrl-[,x".parse().unwrap();
+        assert_eq!(keys.0, vec![0x00, 0x1b, b'x']);
+
+        assert!("".parse::<DetachKeys>().is_err());
+        assert!("ctrl-".parse::<DetachKeys>().is_err());
+        assert!("ctrl-1".parse::<DetachKeys>().is_err());
+        assert!("ab".parse::<DetachKeys>().is_err());
+        assert!("a,,b".parse::<DetachKeys>().is_err());
+    }
+
+    #[test]
+    fn detach_keys_split_across_reads() {
+        let (mut stream, mut client) = pair_with(false, Some("ctrl-p,ctrl-q"));
+        client.write_all(b"ab\x10").unwrap();
+        assert_eq!(receive(&mut stream), vec![Msg::Data(b"ab".to_vec())]);
+        client.write_all(b"\x11cd").unwrap();
+        assert_eq!(receive(&mut stream), vec![Msg::Detach]);
+    }
+
+    #[test]
+    fn broken_detach_keys_are_forwarded() {
+        let (mut stream, mut client) = pair_with(false, Some("ctrl-p,ctrl-q"));
+        client.write_all(b"\x10").unwrap();
+        assert_eq!(receive(&mut stream), vec![]);
+        client.write_all(b"x\x10\x10").unwrap();
+        assert_eq!(receive(&mut stream), vec![Msg::Data(b"\x10x\x10".to_vec())]);
+        client.write_all(b"\x11").unwrap();
+        assert_eq!(receive(&mut stream), vec![Msg::Detach]);
+    }
+
+    #[test]
+    fn repeated_detach_keys() {
+        let (mut stream, mut client) = pair_with(false, Some("a,a,b"));
+        client.write_all(b"aaab").unwrap();
+        assert_eq!(
+            receive(&mut stream),
+            vec![Msg::Data(b"a".to_vec()), Msg::Detach]
+        );
+
+        let (mut stream, mut client) = pair_with(false, Some("a,b,a,c"));
+        client.write_all(b"ababac").unwrap();
+        assert_eq!(
+            receive(&mut stream),
+            vec![Msg::Data(b"ab".to_vec()), Msg::Detach]
+        );
+    }
+
+    #[test]
+    fn framed_clients_detach_keys() {
+        let (mut stream, mut client) = pair_with(false, Some("ctrl-p,ctrl-q"));
+        client
+            .write_all(&frame(FRAME_HELLO, br#"{"version": 1}"#))
+            .unwrap();
+        client.write_all(&frame(FRAME_STDIN, b"a\x10")).unwrap();
+        client
+            .write_all(&frame(FRAME_RESIZE, &[0, 24, 0, 80]))
+            .unwrap();
+        client.write_all(&frame(FRAME_STDIN, b"\x11")).unwrap();
+        assert_eq!(
+            receive(&mut stream),
+            vec![
+                Msg::Hello(1),
+                Msg::Data(b"a".to_vec()),
+                Msg::Resize(24, 80),
+                Msg::Detach,
+            ]
+        );
+    }
+}
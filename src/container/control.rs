@@ -0,0 +1,153 @@
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::str::FromStr;
+
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+
+use super::exit::ExitStatus;
+
+const BUF_SIZE: usize = 4 * 1024;
+const MAX_REQUEST_SIZE: usize = 64 * 1024;
+
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    Status,
+    Kill { signal: SignalSpec },
+    CloseStdin,
+    ReopenLogs,
+    Wait,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum SignalSpec {
+    Number(i32),
+    Name(String),
+}
+
+impl SignalSpec {
+    // Accepts 15, "15", "SIGTERM", and "TERM".
+    pub fn to_signal(&self) -> Option<Signal> {
+        match self {
+            Self::Number(signo) => Signal::try_from(*signo).ok(),
+            Self::Name(name) => match name.parse::<i32>() {
+                Ok(signo) => Signal::try_from(signo).ok(),
+                Err(_) => Signal::from_str(name)
+                    .or_else(|_| Signal::from_str(&format!("SIG{}", name)))
+                    .ok(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "result", rename_all = "kebab-case")]
+pub enum Response<'a> {
+    Ok,
+    Error {
+        message: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    Status {
+        container_pid: i32,
+        uptime_secs: u64,
+        attached_clients: usize,
+        stdin_open: bool,
+    },
+    Exit(&'a ExitStatus),
+}
+
+// Replies are written without blocking, the rest is kept in the outbox until
+// the socket is writable again. Requests of a client that doesn't read its
+// replies aren't read either (see wants_write()), so the outbox stays small.
+pub struct Stream {
+    inner: UnixStream,
+    pending: Vec<u8>,
+    outbox: Vec<u8>,
+}
+
+impl Stream {
+    pub fn new(inner: UnixStream) -> Self {
+        inner
+            .set_nonblocking(true)
+            .expect("Couldn't set control stream nonblocking");
+
+        Self {
+            inner,
+            pending: Vec::new(),
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn wants_write(&self) -> bool {
+        !self.outbox.is_empty()
+    }
+
+    // Returns None on EOF. Malformed lines are reported as Err(message)
+    // to let the caller respond with an error and keep serving the client.
+    pub fn receive(&mut self) -> io::Result<Option<Vec<Result<Request, String>>>> {
+        let mut buf = [0; BUF_SIZE];
+        let nread = match self.inner.read(&mut buf) {
+            Ok(nread) => nread,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Some(Vec::new())),
+            Err(err) => return Err(err),
+        };
+        if nread == 0 {
+            return Ok(None);
+        }
+
+        self.pending.extend_from_slice(&buf[..nread]);
+
+        let mut requests = Vec::new();
+        while let Some(pos) = self.pending.iter().position(|c| *c == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=pos).collect();
+            if line.iter().all(|c| c.is_ascii_whitespace()) {
+                continue;
+            }
+            requests.push(serde_json::from_slice(&line).map_err(|err| err.to_string()));
+        }
+
+        if self.pending.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "control request is too long",
+            ));
+        }
+        Ok(Some(requests))
+    }
+
+    pub fn respond(&mut self, response: &Response) -> io::Result<()> {
+        serde_json::to_writer(&mut self.outbox, response).expect("JSON serialization failed");
+        self.outbox.push(b'\n');
+        self.flush_outbox()
+    }
+
+    // Writes as much of the outbox as the socket takes without blocking.
+    pub fn flush_outbox(&mut self) -> io::Result<()> {
+        let mut nwritten = 0;
+        let res = loop {
+            if nwritten == self.outbox.len() {
+                break Ok(());
+            }
+            match self.inner.write(&self.outbox[nwritten..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => nwritten += n,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => break Err(err),
+            }
+        };
+        self.outbox.drain(..nwritten);
+        res
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::nixtools::process::TerminationStatus;
+
+#[derive(Clone, Serialize)]
+pub struct ExitStatus {
+    at: String,
+    reason: &'static str,
+    #[serde(rename = "exitCode", skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signal: Option<i32>,
+    #[serde(skip)]
+    status: TerminationStatus,
+}
+
+impl ExitStatus {
+    pub fn new(status: TerminationStatus) -> Self {
+        let (reason, exit_code, signal) = match status {
+            TerminationStatus::Exited(.., code) => ("exited", Some(code), None),
+            TerminationStatus::Signaled(.., sig) => ("signaled", None, Some(sig as libc::c_int)),
+        };
+
+        Self {
+            at: Utc::now().to_rfc3339(),
+            reason,
+            exit_code,
+            signal,
+            status,
+        }
+    }
+
+    pub fn to_json(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("JSON serialization failed")
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.status, self.at)
+    }
+}
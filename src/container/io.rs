@@ -1,12 +1,14 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::io::{self, Read, Write};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
 use std::result;
 
-use log::warn;
+use log::{debug, warn};
 use mio::{event::Evented, unix::EventedFd, Poll, PollOpt, Ready, Token};
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
 
 use crate::nixtools::stdio::{IStream, OStream};
 
@@ -18,55 +20,101 @@ pub enum Error {
     Source(io::Error),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Sink(err) => write!(f, "sink: {}", err),
+            Self::Source(err) => write!(f, "source: {}", err),
+        }
+    }
+}
+
 type Result = result::Result<usize, Error>;
 
+// Data not yet accepted by the container's STDIN is kept in a pending buffer.
+// Once the buffer reaches this size the reactor stops reading attach clients.
+const PENDING_MAX_SIZE: usize = 1024 * 1024;
+
 pub struct Gatherer {
     sink: OStream,
-    sources: HashMap<Token, Rc<RefCell<dyn Read>>>,
+    pending: VecDeque<u8>,
 }
 
 impl Gatherer {
     pub fn new(sink: OStream) -> Self {
+        set_nonblocking(sink.as_raw_fd()).expect("Couldn't set container's STDIN nonblocking");
         Self {
-            sink: sink,
-            sources: HashMap::new(),
+            sink,
+            pending: VecDeque::new(),
         }
     }
 
-    pub fn gather(&mut self, token: Token) -> Result {
-        match self.sources.get(&token) {
-            Some(source) => {
-                let mut buf = [0; BUF_SIZE];
-                let nread = source
-                    .borrow_mut()
-                    .read(&mut buf)
-                    .map_err(|err| Error::Source(err))?;
-
-                self.sink
-                    .write_all(&buf[..nread])
-                    .map_err(|err| Error::Sink(err))?;
-                Ok(nread)
-            }
+    // Never blocks. Whatever the sink doesn't accept right away stays
+    // pending until the next flush() on writable readiness.
+    pub fn gather(&mut self, buf: &[u8]) -> Result {
+        self.pending.extend(buf);
+        self.flush()?;
+        Ok(buf.len())
+    }
 
-            None => {
-                warn!(
-                    "[shim] dubious, cannot find source stream for token {:?}",
-                    token
-                );
-                Ok(0)
+    pub fn flush(&mut self) -> Result {
+        let mut nwritten = 0;
+        while !self.pending.is_empty() {
+            let (head, _) = self.pending.as_slices();
+            match self.sink.write(head) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.pending.drain(..n);
+                    nwritten += n;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(Error::Sink(err)),
             }
         }
+        Ok(nwritten)
     }
 
-    pub fn add_source(&mut self, token: Token, source: Rc<RefCell<dyn Read>>) {
-        self.sources.insert(token, source);
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
     }
 
-    pub fn remove_source(&mut self, token: Token) {
-        self.sources.remove(&token);
+    pub fn is_full(&self) -> bool {
+        self.pending.len() >= PENDING_MAX_SIZE
     }
 }
 
+impl Evented for Gatherer {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.sink.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.sink.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.sink.as_raw_fd()).deregister(poll)
+    }
+}
+
+fn set_nonblocking(fd: RawFd) -> nix::Result<()> {
+    let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK)).map(drop)
+}
+
 #[repr(u8)]
 #[derive(Copy, Clone)]
 enum ScattererKind {
@@ -79,39 +127,52 @@ pub struct Scatterer {
     source: IStream,
     sinks: HashMap<usize, Rc<RefCell<dyn Write>>>,
     next_sink_seq_no: usize,
+    history: VecDeque<u8>,
+    history_size: usize,
 }
 
 impl Scatterer {
-    pub fn stdout(source: IStream) -> Self {
-        Self::new(ScattererKind::STDOUT, source)
+    pub fn stdout(source: IStream, history_size: usize) -> Self {
+        Self::new(ScattererKind::STDOUT, source, history_size)
     }
 
-    pub fn stderr(source: IStream) -> Self {
-        Self::new(ScattererKind::STDERR, source)
+    pub fn stderr(source: IStream, history_size: usize) -> Self {
+        Self::new(ScattererKind::STDERR, source, history_size)
     }
 
-    fn new(kind: ScattererKind, source: IStream) -> Self {
+    fn new(kind: ScattererKind, source: IStream, history_size: usize) -> Self {
         Self {
             kind: kind,
             source: source,
             sinks: HashMap::new(),
             next_sink_seq_no: 0,
+            history: VecDeque::with_capacity(history_size),
+            history_size,
         }
     }
 
     pub fn scatter(&mut self) -> Result {
         let mut buf = [0; BUF_SIZE];
-        let nread = self
-            .source
-            .read(&mut buf[1..])
-            .map_err(|err| Error::Source(err))?;
+        let nread = match self.source.read(&mut buf[1..]) {
+            Ok(nread) => nread,
+            // Reading from a PTY master whose slave side
+            // has been closed by the container yields EIO.
+            Err(err) if err.raw_os_error() == Some(libc::EIO) => 0,
+            Err(err) => return Err(Error::Source(err)),
+        };
 
         buf[0] = self.kind as u8;
 
         if nread > 0 {
+            self.remember(&buf[1..nread + 1]);
             self.sinks.retain(|idx, writer| {
                 match writer.borrow_mut().write_all(&buf[..nread + 1]) {
                     Ok(_) => true,
+                    // The sink has been closed on purpose, e.g. a detached client.
+                    Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                        debug!("[shim] dropping closed sink #{}", idx);
+                        false
+                    }
                     Err(err) => {
                         warn!("[shim] failed to scatter STDIO to sink #{}: {}", idx, err);
                         false
@@ -126,6 +187,31 @@ impl Scatterer {
         self.sinks.insert(self.next_sink_seq_no, sink);
         self.next_sink_seq_no += 1;
     }
+
+    // Returns up to `limit` most recent bytes prefixed with the stream byte
+    // (i.e. in the same form sinks receive chunks), or None if history is off.
+    pub fn history(&self, limit: usize) -> Option<Vec<u8>> {
+        if self.history_size == 0 {
+            return None;
+        }
+
+        let skip = self.history.len().saturating_sub(limit);
+        let mut chunk = Vec::with_capacity(1 + self.history.len() - skip);
+        chunk.push(self.kind as u8);
+        chunk.extend(self.history.iter().skip(skip));
+        Some(chunk)
+    }
+
+    fn remember(&mut self, data: &[u8]) {
+        if self.history_size == 0 {
+            return;
+        }
+
+        let data = &data[data.len().saturating_sub(self.history_size)..];
+        let excess = (self.history.len() + data.len()).saturating_sub(self.history_size);
+        self.history.drain(..excess);
+        self.history.extend(data);
+    }
 }
 
 impl Evented for Scatterer {
@@ -0,0 +1,205 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Sender};
+use std::thread::{self, JoinHandle};
+
+use flate2::write::GzEncoder;
+use log::{debug, error};
+
+use super::segments;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::Gzip => ".gz",
+            Self::Zstd => ".zst",
+        }
+    }
+
+    fn compress(&self, src: &Path, dst: &Path) -> io::Result<()> {
+        let mut input = File::open(src)?;
+        let output = File::create(dst)?;
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(output, flate2::Compression::default());
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?.sync_all()
+            }
+            Self::Zstd => {
+                let mut encoder = zstd::Encoder::new(output, 0)?;
+                io::copy(&mut input, &mut encoder)?;
+                encoder.finish()?.sync_all()
+            }
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!("unknown log compression {}", s)),
+        }
+    }
+}
+
+// Compresses rotated log segments in a background thread. The thread also
+// does the renaming of the numbered segments (see segments::shift()), so that
+// the rotation itself stays a single rename and segments never get renamed
+// under the compression. Staged segments are processed in order.
+pub struct Compressor {
+    sender: Option<Sender<PathBuf>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Compressor {
+    pub fn new(path: &Path, max_files: usize, compression: Compression) -> Self {
+        let path = path.to_path_buf();
+        let (sender, receiver) = channel::<PathBuf>();
+
+        let thread = thread::Builder::new()
+            .name("log-compressor".into())
+            .spawn(move || {
+                for staged in receiver {
+                    if let Err(err) = process(&path, max_files, compression, &staged) {
+                        error!(
+                            "[shim] failed to compress rotated log {}: {}",
+                            staged.display(),
+                            err
+                        );
+                    }
+                }
+            })
+            .expect("failed to start log compressor thread");
+
+        Self {
+            sender: Some(sender),
+            thread: Some(thread),
+        }
+    }
+
+    pub fn submit(&self, staged: PathBuf) {
+        if let Some(sender) = self.sender.as_ref() {
+            sender.send(staged).expect("log compressor thread has gone");
+        }
+    }
+
+    // Waits for the already submitted segments to be compressed. It may take
+    // a while, so it's done only once the container's exit status is saved
+    // (see Reactor::finish()).
+    pub fn finish(&mut self) {
+        drop(self.sender.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("[shim] log compressor thread panicked");
+            }
+        }
+    }
+}
+
+// On a compression failure, the segment is kept uncompressed.
+fn process(
+    path: &Path,
+    max_files: usize,
+    compression: Compression,
+    staged: &Path,
+) -> io::Result<()> {
+    segments::shift(path, max_files)?;
+
+    // Readers never see a partially compressed segment.
+    let compressed = segments::numbered(path, 1, compression.extension());
+    let tmp = segments::numbered(path, 1, &format!("{}.tmp", compression.extension()));
+    debug!("[shim] compressing rotated log to {}", compressed.display());
+    match compression.compress(staged, &tmp) {
+        Ok(_) => {
+            fs::rename(&tmp, &compressed)?;
+            fs::remove_file(staged)
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&tmp);
+            fs::rename(staged, segments::numbered(path, 1, ""))?;
+            Err(err)
+        }
+    }
+}
+
+// Picks the decompression by the segment's extension (see segments.rs),
+// log content itself may start with anything (e.g. in the raw format).
+pub fn decoder(path: &Path, input: File) -> io::Result<Box<dyn io::Read>> {
+    let input = io::BufReader::new(input);
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gz") => Ok(Box::new(flate2::bufread::MultiGzDecoder::new(input))),
+        Some("zst") => Ok(Box::new(zstd::Decoder::with_buffer(input)?)),
+        _ => Ok(Box::new(input)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use super::super::reader::Reader;
+
+    fn roundtrip(compression: Compression) {
+        let dir = std::env::temp_dir().join(format!(
+            "shimmy-test-compress-{}-{:?}",
+            std::process::id(),
+            compression
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("log");
+
+        let mut compressor = Compressor::new(&path, 3, compression);
+        for (seq, content) in ["one\n", "two\n", "three\n"].iter().enumerate() {
+            let staged = segments::staged(&path, seq as u128);
+            fs::write(&staged, content).unwrap();
+            compressor.submit(staged);
+        }
+        compressor.finish();
+        fs::write(&path, "four\n").unwrap();
+
+        // The oldest segment is over max_files.
+        let ext = compression.extension();
+        let segments: Vec<PathBuf> = Reader::open(&path).unwrap().segments().cloned().collect();
+        assert_eq!(
+            segments,
+            [
+                segments::numbered(&path, 2, ext),
+                segments::numbered(&path, 1, ext),
+                path.clone(),
+            ]
+        );
+
+        let mut content = String::new();
+        Reader::open(&path)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "two\nthree\nfour\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gzip_rotated_segments() {
+        roundtrip(Compression::Gzip);
+    }
+
+    #[test]
+    fn zstd_rotated_segments() {
+        roundtrip(Compression::Zstd);
+    }
+}
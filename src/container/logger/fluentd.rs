@@ -0,0 +1,335 @@
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use log::info;
+use rmp::encode;
+
+use super::sink::{Line, Sink};
+use super::{drain_queue, expand_tag, resolve, with_default_port, StreamQueue};
+
+const DEFAULT_PORT: u16 = 24224;
+
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_secs(1);
+
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub enum Address {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    // tcp://host[:port] or unix:///path/to/socket
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("://") {
+            Some(("tcp", hostport)) if !hostport.is_empty() => {
+                Ok(Self::Tcp(with_default_port(hostport, DEFAULT_PORT)))
+            }
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            _ => Err(format!("unsupported fluentd address {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub address: Address,
+    // See expand_tag(), plus {stream} is replaced with stdout or stderr.
+    pub tag: String,
+    // Max size of records kept while the collector is unavailable.
+    pub buffer_size: usize,
+    pub container_id: String,
+    pub container_name: Option<String>,
+}
+
+// Host names are resolved once, reconnects reuse the address.
+enum Target {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Target {
+    fn resolve(address: &Address) -> io::Result<Self> {
+        match address {
+            Address::Tcp(hostport) => Ok(Self::Tcp(resolve(hostport)?)),
+            Address::Unix(path) => Ok(Self::Unix(path.clone())),
+        }
+    }
+}
+
+enum Conn {
+    Tcp(mio::net::TcpStream),
+    Unix(UnixStream),
+}
+
+impl Conn {
+    // Doesn't wait for a TCP connection to be established, writes
+    // just would block until then.
+    fn open(target: &Target) -> io::Result<Self> {
+        match target {
+            Target::Tcp(addr) => Ok(Self::Tcp(mio::net::TcpStream::connect(addr)?)),
+            Target::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_nonblocking(true)?;
+                Ok(Self::Unix(stream))
+            }
+        }
+    }
+}
+
+impl Write for Conn {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Speaks the Fluentd Forward protocol: every container output chunk becomes
+// a Forward mode message [tag, [[time, record], ...]] with one record per
+// line. Up to buffer_size of messages is queued (see StreamQueue) while the
+// collector is slow or unavailable, reconnecting with a backoff.
+pub struct Fluentd {
+    config: Config,
+    target: Target,
+    stdout_tag: String,
+    stderr_tag: String,
+    conn: Option<Conn>,
+    backoff: Duration,
+    queue: StreamQueue,
+}
+
+impl Fluentd {
+    pub fn new(config: Config) -> io::Result<Self> {
+        let target = Target::resolve(&config.address).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("fluentd address {:?}: {}", config.address, err),
+            )
+        })?;
+        let tag = expand_tag(
+            &config.tag,
+            &config.container_id,
+            config.container_name.as_deref(),
+        );
+
+        Ok(Self {
+            stdout_tag: tag.replace("{stream}", "stdout"),
+            stderr_tag: tag.replace("{stream}", "stderr"),
+            queue: StreamQueue::new("fluentd", config.buffer_size),
+            config,
+            target,
+            conn: None,
+            backoff: RECONNECT_BACKOFF_MIN,
+        })
+    }
+
+    fn encode(&self, time: &DateTime<Utc>, stream: &'static str, lines: &[Line]) -> Vec<u8> {
+        let tag = if stream == "stderr" {
+            &self.stderr_tag
+        } else {
+            &self.stdout_tag
+        };
+        let mut message = Vec::with_capacity(lines.iter().map(|l| l.data.len() + 128).sum());
+
+        encode::write_array_len(&mut message, 2).unwrap();
+        encode::write_str(&mut message, tag).unwrap();
+        encode::write_array_len(&mut message, lines.len() as u32).unwrap();
+        for line in lines {
+            encode::write_array_len(&mut message, 2).unwrap();
+            write_event_time(&mut message, time);
+
+            let nfields = 3 + self.config.container_name.is_some() as u32 + !line.full as u32;
+            encode::write_map_len(&mut message, nfields).unwrap();
+            // Same record fields as Docker's fluentd driver uses.
+            encode::write_str(&mut message, "log").unwrap();
+            write_raw_str(&mut message, line.data);
+            encode::write_str(&mut message, "source").unwrap();
+            encode::write_str(&mut message, stream).unwrap();
+            encode::write_str(&mut message, "container_id").unwrap();
+            encode::write_str(&mut message, &self.config.container_id).unwrap();
+            if let Some(name) = self.config.container_name.as_ref() {
+                encode::write_str(&mut message, "container_name").unwrap();
+                encode::write_str(&mut message, name).unwrap();
+            }
+            if !line.full {
+                encode::write_str(&mut message, "partial_message").unwrap();
+                encode::write_str(&mut message, "true").unwrap();
+            }
+        }
+        message
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+
+        if self.conn.is_none() {
+            if Instant::now() < self.queue.retry_at() {
+                return Ok(());
+            }
+            match Conn::open(&self.target) {
+                Ok(conn) => {
+                    info!("[shim] connecting to fluentd at {:?}", self.config.address);
+                    self.conn = Some(conn);
+                }
+                Err(err) => {
+                    self.disconnect();
+                    return Err(err);
+                }
+            }
+        }
+
+        match self.queue.write_to(self.conn.as_mut().unwrap()) {
+            Ok(0) => Ok(()),
+            Ok(_) => {
+                self.backoff = RECONNECT_BACKOFF_MIN;
+                Ok(())
+            }
+            Err(err) => {
+                self.disconnect();
+                Err(err)
+            }
+        }
+    }
+
+    fn disconnect(&mut self) {
+        self.conn = None;
+        self.queue.reset();
+        self.queue.delay_until(Instant::now() + self.backoff);
+        self.backoff = (self.backoff * 2).min(RECONNECT_BACKOFF_MAX);
+    }
+}
+
+impl Sink for Fluentd {
+    fn name(&self) -> &'static str {
+        "fluentd"
+    }
+
+    fn write_lines(
+        &mut self,
+        time: &DateTime<Utc>,
+        stream: &'static str,
+        lines: &[Line],
+    ) -> io::Result<()> {
+        let message = self.encode(time, stream, lines);
+        self.queue.push(message);
+        self.queue.report_dropped();
+        self.send()
+    }
+
+    fn retry_deadline(&self) -> Option<Instant> {
+        self.queue.retry_deadline()
+    }
+
+    fn retry(&mut self) -> io::Result<()> {
+        self.send()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        drain_queue(self, |fluentd| &fluentd.queue, Self::send)
+    }
+}
+
+// EventTime extension: seconds and nanoseconds as big-endian u32s.
+fn write_event_time(out: &mut Vec<u8>, time: &DateTime<Utc>) {
+    encode::write_ext_meta(out, 8, 0).unwrap();
+    out.extend_from_slice(&(time.timestamp() as u32).to_be_bytes());
+    out.extend_from_slice(&time.timestamp_subsec_nanos().to_be_bytes());
+}
+
+// Container output isn't necessarily valid UTF-8, but collectors expect
+// log to be a string, so it's passed through as is.
+fn write_raw_str(out: &mut Vec<u8>, data: &[u8]) {
+    encode::write_str_len(out, data.len() as u32).unwrap();
+    out.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn str8(out: &mut Vec<u8>, s: &[u8]) {
+        out.push(0xa0 | s.len() as u8);
+        out.extend_from_slice(s);
+    }
+
+    #[test]
+    fn forward_mode_message() {
+        let fluentd = Fluentd::new(Config {
+            address: Address::Unix(PathBuf::from("/nonexistent")),
+            tag: "{name}.{stream}".into(),
+            buffer_size: 1024,
+            container_id: "c1".into(),
+            container_name: Some("web".into()),
+        })
+        .unwrap();
+        let time = Utc.timestamp_opt(1_700_000_000, 5).unwrap();
+        let lines = [
+            Line {
+                data: b"hi",
+                full: true,
+            },
+            Line {
+                data: b"\xff",
+                full: false,
+            },
+        ];
+
+        let mut record = vec![0x92, 0xd7, 0x00];
+        record.extend_from_slice(&1_700_000_000u32.to_be_bytes());
+        record.extend_from_slice(&5u32.to_be_bytes());
+        let mut expected = vec![0x92];
+        str8(&mut expected, b"web.stderr");
+        expected.push(0x92);
+
+        expected.extend_from_slice(&record);
+        expected.push(0x84);
+        for s in [
+            &b"log"[..],
+            b"hi",
+            b"source",
+            b"stderr",
+            b"container_id",
+            b"c1",
+        ] {
+            str8(&mut expected, s);
+        }
+        for s in [&b"container_name"[..], b"web"] {
+            str8(&mut expected, s);
+        }
+
+        expected.extend_from_slice(&record);
+        expected.push(0x85);
+        for s in [
+            &b"log"[..],
+            b"\xff",
+            b"source",
+            b"stderr",
+            b"container_id",
+            b"c1",
+        ] {
+            str8(&mut expected, s);
+        }
+        for s in [&b"container_name"[..], b"web", b"partial_message", b"true"] {
+            str8(&mut expected, s);
+        }
+
+        assert_eq!(fluentd.encode(&time, "stderr", &lines), expected);
+    }
+}
@@ -0,0 +1,158 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+
+// Renders a single log line into its on-disk record. `full` is false for
+// lines continued in the next record (split for being too long or not
+// finished in time).
+pub trait Formatter {
+    fn format(
+        &self,
+        out: &mut Vec<u8>,
+        time: &DateTime<Utc>,
+        stream: &str,
+        line: &[u8],
+        full: bool,
+    );
+}
+
+// <rfc3339> <stream> <line>, empty lines are skipped.
+pub struct Text;
+
+impl Formatter for Text {
+    fn format(&self, out: &mut Vec<u8>, time: &DateTime<Utc>, stream: &str, line: &[u8], _: bool) {
+        if line.is_empty() {
+            return;
+        }
+
+        out.extend_from_slice(time.to_rfc3339().as_bytes());
+        out.push(b' ');
+        out.extend_from_slice(stream.as_bytes());
+        out.push(b' ');
+        out.extend_from_slice(String::from_utf8_lossy(line).as_bytes());
+        out.push(b'\n');
+    }
+}
+
+// <rfc3339nano> <stream> <P|F> <line>, as kubelet expects it.
+pub struct Cri;
+
+impl Formatter for Cri {
+    fn format(
+        &self,
+        out: &mut Vec<u8>,
+        time: &DateTime<Utc>,
+        stream: &str,
+        line: &[u8],
+        full: bool,
+    ) {
+        out.extend_from_slice(time.to_rfc3339_opts(SecondsFormat::Nanos, true).as_bytes());
+        out.push(b' ');
+        out.extend_from_slice(stream.as_bytes());
+        out.extend_from_slice(if full { b" F " } else { b" P " });
+        out.extend_from_slice(line);
+        out.push(b'\n');
+    }
+}
+
+// Docker's json-file: {"log":"<line>\n","stream":"stdout","time":"<rfc3339nano>"}
+// Partial lines lack the trailing newline in "log". Like Docker, invalid
+// UTF-8 sequences are replaced with U+FFFD.
+pub struct JsonFile;
+
+#[derive(Serialize)]
+struct JsonFileRecord<'a> {
+    log: &'a str,
+    stream: &'a str,
+    time: String,
+}
+
+impl Formatter for JsonFile {
+    fn format(
+        &self,
+        out: &mut Vec<u8>,
+        time: &DateTime<Utc>,
+        stream: &str,
+        line: &[u8],
+        full: bool,
+    ) {
+        let mut log = String::from_utf8_lossy(line).into_owned();
+        if full {
+            log.push('\n');
+        }
+
+        let record = JsonFileRecord {
+            log: &log,
+            stream,
+            time: time.to_rfc3339_opts(SecondsFormat::Nanos, true),
+        };
+        serde_json::to_writer(&mut *out, &record).expect("JSON serialization failed");
+        out.push(b'\n');
+    }
+}
+
+// Binary-safe records, the output is stored exactly as produced:
+// <u64 BE unix time nanos> <u8 stream> <u32 BE length> <payload>
+// Stream is 1 for stdout and 2 for stderr. The payload of a full line
+// ends with its newline, so concatenating the payloads of a stream's
+// records gives back the original output (see reader::RawRecords).
+pub struct Raw;
+
+pub const RAW_STDOUT: u8 = 1;
+pub const RAW_STDERR: u8 = 2;
+pub const RAW_HEADER_SIZE: usize = 8 + 1 + 4;
+
+impl Formatter for Raw {
+    fn format(
+        &self,
+        out: &mut Vec<u8>,
+        time: &DateTime<Utc>,
+        stream: &str,
+        line: &[u8],
+        full: bool,
+    ) {
+        // Out of range only past the year 2262.
+        let nanos = time.timestamp_nanos_opt().unwrap_or(0) as u64;
+        let len = line.len() + full as usize;
+
+        out.extend_from_slice(&nanos.to_be_bytes());
+        out.push(if stream == "stderr" {
+            RAW_STDERR
+        } else {
+            RAW_STDOUT
+        });
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+        out.extend_from_slice(line);
+        if full {
+            out.push(b'\n');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn json_file(line: &[u8], full: bool) -> String {
+        let time = Utc.timestamp_opt(1_700_000_000, 5).unwrap();
+        let mut out = Vec::new();
+        JsonFile.format(&mut out, &time, "stdout", line, full);
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn json_file_escaping() {
+        let tail = r#"","stream":"stdout","time":"2023-11-14T22:13:20.000000005Z"}"#;
+        for (line, full, log) in [
+            (&b"say \"hi\" \\o/"[..], true, r#"say \"hi\" \\o/\n"#),
+            (b"\x1b[1m\tbold\x00", true, r#"\u001b[1m\tbold\u0000\n"#),
+            (b"a\nb", false, r#"a\nb"#),
+            (b"bad \xff\xfe utf-8", true, "bad \u{fffd}\u{fffd} utf-8\\n"),
+        ] {
+            assert_eq!(
+                json_file(line, full),
+                format!("{{\"log\":\"{}{}\n", log, tail)
+            );
+        }
+    }
+}
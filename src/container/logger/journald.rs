@@ -0,0 +1,279 @@
+use std::ffi::CString;
+use std::io::{self, IoSlice};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::warn;
+use nix::fcntl::{fcntl, FcntlArg, SealFlag};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags, UnixAddr};
+
+use super::sink::{Line, Sink};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub socket_path: PathBuf,
+    pub container_id: String,
+    pub container_name: Option<String>,
+}
+
+// Speaks the journal native protocol: one datagram per entry made of
+// KEY=value lines (see systemd's JOURNAL_NATIVE_PROTOCOL). Entries too big
+// for a datagram are passed as a sealed memfd instead.
+pub struct Journald {
+    config: Config,
+    socket: UnixDatagram,
+    ndropped: usize,
+}
+
+impl Journald {
+    pub fn new(config: Config) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        // A stuck journal must never stall the container output.
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            config,
+            socket,
+            ndropped: 0,
+        })
+    }
+
+    fn send(&mut self, entry: &[u8]) -> io::Result<()> {
+        match self.socket.send_to(entry, &self.config.socket_path) {
+            Ok(_) => Ok(()),
+            Err(err) if err.raw_os_error() == Some(libc::EMSGSIZE) => {
+                send_memfd(&self.socket, &self.config.socket_path, entry)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Sink for Journald {
+    fn name(&self) -> &'static str {
+        "journald"
+    }
+
+    fn write_lines(
+        &mut self,
+        _: &DateTime<Utc>,
+        stream: &'static str,
+        lines: &[Line],
+    ) -> io::Result<()> {
+        // Same priorities as Docker's journald driver uses.
+        let priority: &[u8] = if stream == "stderr" { b"3" } else { b"6" };
+
+        for line in lines {
+            let mut entry = Vec::with_capacity(line.data.len() + 128);
+            append_field(&mut entry, "MESSAGE", line.data);
+            append_field(&mut entry, "PRIORITY", priority);
+            append_field(
+                &mut entry,
+                "CONTAINER_ID",
+                self.config.container_id.as_bytes(),
+            );
+            if let Some(name) = self.config.container_name.as_ref() {
+                append_field(&mut entry, "CONTAINER_NAME", name.as_bytes());
+            }
+            append_field(&mut entry, "CONTAINER_STREAM", stream.as_bytes());
+            if !line.full {
+                append_field(&mut entry, "CONTAINER_PARTIAL_MESSAGE", b"true");
+            }
+
+            match self.send(&entry) {
+                Ok(_) => (),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.ndropped += 1,
+                Err(err) => return Err(err),
+            }
+        }
+
+        if self.ndropped > 0 {
+            warn!(
+                "[shim] journal is not keeping up, dropped {} log entries",
+                self.ndropped
+            );
+            self.ndropped = 0;
+        }
+        Ok(())
+    }
+}
+
+// Values with newlines (or arbitrary binary data) use the length-prefixed form:
+// KEY\n<u64 LE length><value>\n
+fn append_field(entry: &mut Vec<u8>, key: &str, value: &[u8]) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains(&b'\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value);
+    entry.push(b'\n');
+}
+
+fn send_memfd(socket: &UnixDatagram, path: &Path, entry: &[u8]) -> io::Result<()> {
+    let name = CString::new("shimmy-journal-entry").unwrap();
+    let fd = memfd_create(
+        &name,
+        MemFdCreateFlag::MFD_CLOEXEC | MemFdCreateFlag::MFD_ALLOW_SEALING,
+    )?;
+    let mut file = unsafe { std::fs::File::from_raw_fd(fd) };
+    io::Write::write_all(&mut file, entry)?;
+
+    // journald accepts only sealed memfds.
+    fcntl(
+        file.as_raw_fd(),
+        FcntlArg::F_ADD_SEALS(
+            SealFlag::F_SEAL_SHRINK
+                | SealFlag::F_SEAL_GROW
+                | SealFlag::F_SEAL_WRITE
+                | SealFlag::F_SEAL_SEAL,
+        ),
+    )?;
+
+    let addr = UnixAddr::new(path)?;
+    let fds = [file.as_raw_fd()];
+    sendmsg(
+        socket.as_raw_fd(),
+        &[] as &[IoSlice],
+        &[ControlMessage::ScmRights(&fds)],
+        MsgFlags::empty(),
+        Some(&addr),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::{IoSliceMut, Read, Seek, SeekFrom};
+    use std::os::unix::io::RawFd;
+
+    use nix::sys::socket::{recvmsg, ControlMessageOwned};
+
+    struct Journal {
+        socket: UnixDatagram,
+        path: PathBuf,
+    }
+
+    impl Journal {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "shimmy-test-journal-{}-{}.sock",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_file(&path);
+            let socket = UnixDatagram::bind(&path).unwrap();
+            Self { socket, path }
+        }
+
+        fn journald(&self) -> Journald {
+            Journald::new(Config {
+                socket_path: self.path.clone(),
+                container_id: "c1".into(),
+                container_name: Some("web".into()),
+            })
+            .unwrap()
+        }
+
+        // Returns the datagram and the fd passed along with it, if any.
+        fn receive(&self) -> (Vec<u8>, Option<RawFd>) {
+            let mut buf = vec![0; 64 * 1024];
+            let mut cmsg = nix::cmsg_space!([RawFd; 1]);
+            let mut iov = [IoSliceMut::new(&mut buf)];
+            let msg = recvmsg::<()>(
+                self.socket.as_raw_fd(),
+                &mut iov,
+                Some(&mut cmsg),
+                MsgFlags::empty(),
+            )
+            .unwrap();
+            let fd = msg.cmsgs().find_map(|cmsg| match cmsg {
+                ControlMessageOwned::ScmRights(fds) => fds.first().copied(),
+                _ => None,
+            });
+            let nbytes = msg.bytes;
+            (buf[..nbytes].to_vec(), fd)
+        }
+    }
+
+    impl Drop for Journal {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn entries() {
+        let journal = Journal::new("entries");
+        let mut journald = journal.journald();
+        let lines = [
+            Line {
+                data: b"hello",
+                full: true,
+            },
+            Line {
+                data: b"multi\nline",
+                full: false,
+            },
+        ];
+        journald.write_lines(&Utc::now(), "stdout", &lines).unwrap();
+        journald
+            .write_lines(&Utc::now(), "stderr", &lines[..1])
+            .unwrap();
+
+        let (entry, fd) = journal.receive();
+        assert_eq!(fd, None);
+        assert_eq!(
+            entry,
+            b"MESSAGE=hello\nPRIORITY=6\nCONTAINER_ID=c1\nCONTAINER_NAME=web\nCONTAINER_STREAM=stdout\n"
+        );
+
+        let (entry, _) = journal.receive();
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&10u64.to_le_bytes());
+        expected.extend_from_slice(b"multi\nline\nPRIORITY=6\n");
+        expected
+            .extend_from_slice(b"CONTAINER_ID=c1\nCONTAINER_NAME=web\nCONTAINER_STREAM=stdout\n");
+        expected.extend_from_slice(b"CONTAINER_PARTIAL_MESSAGE=true\n");
+        assert_eq!(entry, expected);
+
+        let (entry, _) = journal.receive();
+        assert_eq!(
+            entry,
+            b"MESSAGE=hello\nPRIORITY=3\nCONTAINER_ID=c1\nCONTAINER_NAME=web\nCONTAINER_STREAM=stderr\n"
+        );
+    }
+
+    #[test]
+    fn oversized_entries_are_passed_as_memfd() {
+        let journal = Journal::new("memfd");
+        let mut journald = journal.journald();
+        let data = vec![b'x'; 4 * 1024 * 1024];
+        let lines = [Line {
+            data: &data,
+            full: true,
+        }];
+        journald.write_lines(&Utc::now(), "stdout", &lines).unwrap();
+
+        let (datagram, fd) = journal.receive();
+        assert!(datagram.is_empty());
+        let mut memfd = unsafe { File::from_raw_fd(fd.expect("no memfd received")) };
+        let seals = fcntl(memfd.as_raw_fd(), FcntlArg::F_GET_SEALS).unwrap();
+        assert_ne!(seals & libc::F_SEAL_WRITE, 0);
+
+        // The fd shares its offset with the one the entry was written through.
+        let mut entry = Vec::new();
+        memfd.seek(SeekFrom::Start(0)).unwrap();
+        memfd.read_to_end(&mut entry).unwrap();
+        assert!(entry.starts_with(b"MESSAGE="));
+        assert_eq!(&entry[8..8 + data.len()], &data[..]);
+        assert!(entry.ends_with(b"CONTAINER_STREAM=stdout\n"));
+    }
+}
@@ -0,0 +1,678 @@
+pub mod fluentd;
+pub mod journald;
+pub mod reader;
+pub mod syslog_sink;
+
+mod compress;
+mod format;
+mod segments;
+mod sink;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, Write};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use log::{error, warn};
+
+pub use compress::Compression;
+use fluentd::Fluentd;
+
+use format::Formatter;
+use journald::Journald;
+use sink::{FileSink, Line, Sink};
+use syslog_sink::SyslogSink;
+
+// An incomplete line is logged as is if its end doesn't come in time.
+pub const PARTIAL_LINE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Bounds the incomplete line buffer when max_line_size is unlimited.
+const PARTIAL_LINE_MAX_SIZE: usize = 1024 * 1024;
+
+// How often a connected but busy network log receiver is retried.
+const WRITE_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+// A stream connection not accepting any data for that long is considered
+// broken (it also bounds the time to establish a TCP connection).
+const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+// For how long queued messages are still tried to be delivered
+// when the container output is over.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Copy, Clone, Debug)]
+pub enum Format {
+    Text,
+    Cri,
+    JsonFile,
+    Raw,
+}
+
+impl Format {
+    fn formatter(&self) -> Box<dyn Formatter> {
+        match self {
+            Self::Text => Box::new(format::Text),
+            Self::Cri => Box::new(format::Cri),
+            Self::JsonFile => Box::new(format::JsonFile),
+            Self::Raw => Box::new(format::Raw),
+        }
+    }
+
+    // Only kubelet expects long lines to be split (and marked partial),
+    // other formats keep lines whole unless asked otherwise.
+    pub fn default_max_line_size(&self) -> usize {
+        match self {
+            Self::Cri => 16 * 1024,
+            _ => 0,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "cri" => Ok(Self::Cri),
+            "json-file" => Ok(Self::JsonFile),
+            "raw" => Ok(Self::Raw),
+            _ => Err(format!("unknown log format {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub format: Format,
+    // Longer lines are split into partial ones; 0 means no limit.
+    pub max_line_size: usize,
+    // The log file is rotated once it's over max_size; 0 means no limit.
+    pub max_size: u64,
+    // Max number of log files kept, including the current one.
+    pub max_files: usize,
+    // Rotated log files are compressed in the background.
+    pub compression: Option<Compression>,
+    pub journald: Option<journald::Config>,
+    pub syslog: Option<syslog_sink::Config>,
+    pub fluentd: Option<fluentd::Config>,
+}
+
+// Tags of log records may refer to the container: {id}, {short-id}
+// (first 12 characters of the id) and {name} (falls back to the id).
+fn expand_tag(tag: &str, container_id: &str, container_name: Option<&str>) -> String {
+    let short_id: String = container_id.chars().take(12).collect();
+    tag.replace("{id}", container_id)
+        .replace("{short-id}", &short_id)
+        .replace("{name}", container_name.unwrap_or(container_id))
+}
+
+// Network sinks' addresses are host[:port].
+fn with_default_port(hostport: &str, port: u16) -> String {
+    match hostport.rsplit_once(':') {
+        Some((_, p)) if !p.is_empty() && p.bytes().all(|c| c.is_ascii_digit()) => {
+            hostport.to_string()
+        }
+        _ => format!("{}:{}", hostport, port),
+    }
+}
+
+fn resolve(hostport: &str) -> io::Result<SocketAddr> {
+    hostport.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("couldn't resolve {}", hostport),
+        )
+    })
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+// Network sinks queue their messages for a nonblocking stream connection,
+// so a slow or stuck receiver delays the delivery instead of the container
+// output. Only when the queue is over max_size, new messages are dropped.
+struct StreamQueue {
+    sink: &'static str,
+    messages: VecDeque<Vec<u8>>,
+    size: usize,
+    max_size: usize,
+    // Bytes of the front message already written.
+    nwritten: usize,
+    retry_at: Instant,
+    stalled_since: Option<Instant>,
+    ndropped: usize,
+}
+
+impl StreamQueue {
+    fn new(sink: &'static str, max_size: usize) -> Self {
+        Self {
+            sink,
+            messages: VecDeque::new(),
+            size: 0,
+            max_size,
+            nwritten: 0,
+            retry_at: Instant::now(),
+            stalled_since: None,
+            ndropped: 0,
+        }
+    }
+
+    fn push(&mut self, message: Vec<u8>) {
+        if self.size + message.len() > self.max_size {
+            self.ndropped += 1;
+            return;
+        }
+        self.size += message.len();
+        self.messages.push_back(message);
+    }
+
+    // Counts a message lost without being queued, e.g. a datagram.
+    fn drop_message(&mut self) {
+        self.ndropped += 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    fn retry_at(&self) -> Instant {
+        self.retry_at
+    }
+
+    fn retry_deadline(&self) -> Option<Instant> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.retry_at)
+        }
+    }
+
+    // E.g. a reconnect is not due yet.
+    fn delay_until(&mut self, at: Instant) {
+        self.retry_at = at;
+    }
+
+    // Writes out as much of the queue as the stream takes without blocking
+    // and returns the number of messages written in full. After an error
+    // the connection is to be dropped and reset() called.
+    fn write_to<W: Write>(&mut self, stream: &mut W) -> io::Result<usize> {
+        let mut nmessages = 0;
+        while let Some(message) = self.messages.front() {
+            let len = message.len();
+            match stream.write(&message[self.nwritten..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.stalled_since = None;
+                    self.nwritten += n;
+                    if self.nwritten == len {
+                        self.size -= len;
+                        self.messages.pop_front();
+                        self.nwritten = 0;
+                        nmessages += 1;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    let now = Instant::now();
+                    let stalled_since = *self.stalled_since.get_or_insert(now);
+                    if now.duration_since(stalled_since) >= STALL_TIMEOUT {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("{} connection stalled", self.sink),
+                        ));
+                    }
+                    self.retry_at = now + WRITE_RETRY_INTERVAL;
+                    break;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(nmessages)
+    }
+
+    // The connection is gone, a partially written message is written
+    // again in full over the next one.
+    fn reset(&mut self) {
+        self.nwritten = 0;
+        self.stalled_since = None;
+    }
+
+    fn clear(&mut self) {
+        self.reset();
+        self.messages.clear();
+        self.size = 0;
+    }
+
+    fn report_dropped(&mut self) {
+        if self.ndropped > 0 {
+            warn!(
+                "[shim] {} is not keeping up, dropped {} log messages",
+                self.sink, self.ndropped
+            );
+            self.ndropped = 0;
+        }
+    }
+}
+
+// Closing a network sink keeps sending its queued messages
+// until there are none left or CLOSE_TIMEOUT is over.
+fn drain_queue<S>(
+    sink: &mut S,
+    queue: fn(&S) -> &StreamQueue,
+    send: fn(&mut S) -> io::Result<()>,
+) -> io::Result<()> {
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    loop {
+        let res = send(sink);
+        let queue = queue(sink);
+        if queue.is_empty() {
+            return res;
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "gave up delivering {} queued log messages",
+                    queue.messages.len()
+                ),
+            ));
+        }
+        thread::sleep(queue.retry_at.min(deadline).saturating_duration_since(now));
+    }
+}
+
+// Fans container output lines out to the log files and other sinks.
+// Sinks taking only one stream's lines (i.e. per stream log files)
+// are tagged with the stream name.
+pub struct Logger {
+    sinks: Vec<(Option<&'static str>, Box<dyn Sink>)>,
+    max_line_size: usize,
+}
+
+impl Logger {
+    // Streams may be logged to separate files or to the same one. Without
+    // a path, stream's output goes only to the other sinks (if any).
+    // Network sinks' host names are resolved here, once.
+    pub fn new<P: AsRef<Path>>(
+        (stdout_path, stderr_path): (Option<P>, Option<P>),
+        config: Config,
+    ) -> io::Result<Self> {
+        let file_sink = |path: &P| -> io::Result<Box<dyn Sink>> {
+            Ok(Box::new(FileSink::new(
+                path,
+                config.max_size,
+                config.max_files,
+                config.compression,
+                config.format.formatter(),
+            )?))
+        };
+
+        let mut sinks: Vec<(Option<&'static str>, Box<dyn Sink>)> = Vec::new();
+        match (stdout_path, stderr_path) {
+            // Already created by the stdout sink, the file is recognized
+            // under any name (e.g. log and ./log, or a symlink).
+            (Some(stdout), Some(stderr)) => {
+                let sink = file_sink(&stdout)?;
+                if same_file(stdout.as_ref(), stderr.as_ref()) {
+                    sinks.push((None, sink));
+                } else {
+                    sinks.push((Some("stdout"), sink));
+                    sinks.push((Some("stderr"), file_sink(&stderr)?));
+                }
+            }
+            (Some(stdout), None) => sinks.push((Some("stdout"), file_sink(&stdout)?)),
+            (None, Some(stderr)) => sinks.push((Some("stderr"), file_sink(&stderr)?)),
+            (None, None) => (),
+        }
+        if let Some(journald) = config.journald {
+            sinks.push((None, Box::new(Journald::new(journald)?)));
+        }
+        if let Some(syslog) = config.syslog {
+            sinks.push((None, Box::new(SyslogSink::new(syslog)?)));
+        }
+        if let Some(fluentd) = config.fluentd {
+            sinks.push((None, Box::new(Fluentd::new(fluentd)?)));
+        }
+
+        Ok(Self {
+            sinks,
+            max_line_size: config.max_line_size,
+        })
+    }
+
+    fn max_partial_line_size(&self) -> usize {
+        match self.max_line_size {
+            0 => PARTIAL_LINE_MAX_SIZE,
+            size => size.min(PARTIAL_LINE_MAX_SIZE),
+        }
+    }
+
+    // A chunk not ending with a newline leaves its last line partial,
+    // the rest of it comes with the next chunk. A failing sink doesn't
+    // affect the others.
+    pub fn write(&mut self, stream: &'static str, buf: &[u8]) -> io::Result<()> {
+        let time = Utc::now();
+        let max_line_size = match self.max_line_size {
+            0 => usize::MAX,
+            size => size,
+        };
+
+        let mut lines = Vec::new();
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let (line, full) = match rest.iter().position(|c| *c == b'\n') {
+                Some(pos) => {
+                    let line = &rest[..pos];
+                    rest = &rest[pos + 1..];
+                    (line, true)
+                }
+                None => {
+                    let line = rest;
+                    rest = &[];
+                    (line, false)
+                }
+            };
+
+            let mut pieces = line.chunks(max_line_size).peekable();
+            if pieces.peek().is_none() {
+                lines.push(Line { data: b"", full });
+            }
+            while let Some(piece) = pieces.next() {
+                let last = pieces.peek().is_none();
+                lines.push(Line {
+                    data: piece,
+                    full: full && last,
+                });
+            }
+        }
+
+        for (_, sink) in self
+            .sinks
+            .iter_mut()
+            .filter(|(only, _)| only.is_none() || *only == Some(stream))
+        {
+            if let Err(err) = sink.write_lines(&time, stream, &lines) {
+                error!("[shim] {} log sink failed: {}", sink.name(), err);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn retry_deadline(&self) -> Option<Instant> {
+        self.sinks
+            .iter()
+            .filter_map(|(_, sink)| sink.retry_deadline())
+            .min()
+    }
+
+    pub fn retry_expired(&mut self) {
+        let now = Instant::now();
+        for (_, sink) in self.sinks.iter_mut() {
+            if sink
+                .retry_deadline()
+                .is_some_and(|deadline| deadline <= now)
+            {
+                if let Err(err) = sink.retry() {
+                    error!("[shim] {} log sink failed: {}", sink.name(), err);
+                }
+            }
+        }
+    }
+
+    // Every sink is reopened even if some fail, the first error is returned.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        let mut res = Ok(());
+        for (_, sink) in self.sinks.iter_mut() {
+            if let Err(err) = sink.reopen() {
+                error!("[shim] {} log sink failed to reopen: {}", sink.name(), err);
+                res = res.and(Err(err));
+            }
+        }
+        res
+    }
+
+    pub fn close(&mut self) {
+        for (_, sink) in self.sinks.iter_mut() {
+            if let Err(err) = sink.close() {
+                error!("[shim] {} log sink failed: {}", sink.name(), err);
+            }
+        }
+    }
+}
+
+// Lines often come split across several reads, so the trailing incomplete
+// line of a chunk is held back until its end arrives, it grows too long,
+// it's been waiting for too long (see flush_expired()), or flush() is called
+// at the stream end.
+pub struct Writer {
+    logger: Rc<RefCell<Logger>>,
+    stream: &'static str,
+    partial: Vec<u8>,
+    partial_since: Instant,
+}
+
+impl Writer {
+    pub fn stdout(logger: Rc<RefCell<Logger>>) -> Self {
+        Self::new(logger, "stdout")
+    }
+
+    pub fn stderr(logger: Rc<RefCell<Logger>>) -> Self {
+        Self::new(logger, "stderr")
+    }
+
+    fn new(logger: Rc<RefCell<Logger>>, stream: &'static str) -> Self {
+        Self {
+            logger,
+            stream,
+            partial: Vec::new(),
+            partial_since: Instant::now(),
+        }
+    }
+
+    pub fn flush_deadline(&self) -> Option<Instant> {
+        if self.partial.is_empty() {
+            None
+        } else {
+            Some(self.partial_since + PARTIAL_LINE_TIMEOUT)
+        }
+    }
+
+    pub fn flush_expired(&mut self) -> io::Result<()> {
+        match self.flush_deadline() {
+            Some(deadline) if deadline <= Instant::now() => self.flush(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let data = &buf[1..];
+        if self.partial.is_empty() {
+            self.partial_since = Instant::now();
+        }
+
+        match data.iter().rposition(|c| *c == b'\n') {
+            Some(pos) => {
+                self.partial.extend_from_slice(&data[..=pos]);
+                let res = self.logger.borrow_mut().write(self.stream, &self.partial);
+                self.partial.clear();
+                res?;
+
+                self.partial.extend_from_slice(&data[pos + 1..]);
+                self.partial_since = Instant::now();
+            }
+            None => self.partial.extend_from_slice(data),
+        }
+
+        let max_size = self.logger.borrow().max_partial_line_size();
+        while self.partial.len() >= max_size {
+            let res = self
+                .logger
+                .borrow_mut()
+                .write(self.stream, &self.partial[..max_size]);
+            self.partial.drain(..max_size);
+            res?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.partial.is_empty() {
+            return Ok(());
+        }
+
+        let res = self.logger.borrow_mut().write(self.stream, &self.partial);
+        self.partial.clear();
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::DateTime;
+    use format::Formatter;
+
+    // Renders the lines in the CRI format, as a FileSink would.
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Sink for Capture {
+        fn name(&self) -> &'static str {
+            "capture"
+        }
+
+        fn write_lines(
+            &mut self,
+            time: &DateTime<Utc>,
+            stream: &'static str,
+            lines: &[Line],
+        ) -> io::Result<()> {
+            for line in lines {
+                format::Cri.format(&mut self.0.borrow_mut(), time, stream, line.data, line.full);
+            }
+            Ok(())
+        }
+    }
+
+    fn capturing_logger(max_line_size: usize) -> (Logger, Rc<RefCell<Vec<u8>>>) {
+        let out = Rc::new(RefCell::new(Vec::new()));
+        let logger = Logger {
+            sinks: vec![(None, Box::new(Capture(out.clone())))],
+            max_line_size,
+        };
+        (logger, out)
+    }
+
+    // Records without their timestamps.
+    fn records(out: &RefCell<Vec<u8>>) -> Vec<String> {
+        String::from_utf8(out.borrow().clone())
+            .unwrap()
+            .lines()
+            .map(|record| record.split_once(' ').unwrap().1.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn cri_long_lines_are_split() {
+        let (mut logger, out) = capturing_logger(4);
+        logger.write("stdout", b"abcdefghij\nxyz\n").unwrap();
+        assert_eq!(
+            records(&out),
+            [
+                "stdout P abcd",
+                "stdout P efgh",
+                "stdout F ij",
+                "stdout F xyz"
+            ]
+        );
+    }
+
+    #[test]
+    fn cri_line_of_exact_multiple_size() {
+        let (mut logger, out) = capturing_logger(4);
+        logger.write("stderr", b"abcdefgh\nabcd\n").unwrap();
+        assert_eq!(
+            records(&out),
+            ["stderr P abcd", "stderr F efgh", "stderr F abcd"]
+        );
+    }
+
+    #[test]
+    fn cri_empty_lines_are_kept() {
+        let (mut logger, out) = capturing_logger(4);
+        logger.write("stdout", b"\na\n\n\nb").unwrap();
+        assert_eq!(
+            records(&out),
+            [
+                "stdout F ",
+                "stdout F a",
+                "stdout F ",
+                "stdout F ",
+                "stdout P b"
+            ]
+        );
+    }
+
+    fn capturing_writer(max_line_size: usize) -> (Writer, Rc<RefCell<Vec<u8>>>) {
+        let (logger, out) = capturing_logger(max_line_size);
+        (Writer::stdout(Rc::new(RefCell::new(logger))), out)
+    }
+
+    #[test]
+    fn writer_joins_line_split_across_writes() {
+        let (mut writer, out) = capturing_writer(0);
+        writer.write_all(b"\x01par").unwrap();
+        assert!(records(&out).is_empty());
+        assert!(writer.flush_deadline().is_some());
+
+        writer.write_all(b"\x01tial\nnext").unwrap();
+        assert_eq!(records(&out), ["stdout F partial"]);
+        assert_eq!(writer.partial, b"next");
+    }
+
+    #[test]
+    fn writer_flushes_expired_partial_line() {
+        let (mut writer, out) = capturing_writer(0);
+        writer.write_all(b"\x01abc").unwrap();
+        writer.flush_expired().unwrap();
+        assert!(records(&out).is_empty());
+
+        writer.partial_since -= PARTIAL_LINE_TIMEOUT;
+        writer.flush_expired().unwrap();
+        assert_eq!(records(&out), ["stdout P abc"]);
+        assert_eq!(writer.flush_deadline(), None);
+    }
+
+    #[test]
+    fn writer_caps_partial_line() {
+        let (mut writer, out) = capturing_writer(4);
+        writer.write_all(b"\x01abcdefghij").unwrap();
+        assert_eq!(records(&out), ["stdout P abcd", "stdout P efgh"]);
+        assert_eq!(writer.partial, b"ij");
+    }
+
+    #[test]
+    fn writer_flushes_partial_line_at_eof() {
+        let (mut writer, out) = capturing_writer(0);
+        writer.write_all(b"\x01line\ntail").unwrap();
+        writer.flush().unwrap();
+        writer.flush().unwrap();
+        assert_eq!(records(&out), ["stdout F line", "stdout P tail"]);
+    }
+}
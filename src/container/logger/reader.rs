@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use super::compress;
+use super::format;
+use super::segments;
+
+// A rotation renaming the segments while they're being opened makes
+// Reader::open() list them again, that many times at most.
+const OPEN_ATTEMPTS: usize = 5;
+
+// Reads a container log as a whole: the rotated segments, oldest first,
+// followed by the current log file. Compressed segments are decompressed
+// on the fly. All the segments are opened at once, so the log is read as
+// of that moment even if rotated meanwhile (output written after a later
+// rotation is not read though).
+pub struct Reader {
+    path: PathBuf,
+    segments: VecDeque<(PathBuf, File)>,
+    current: Option<Box<dyn Read>>,
+}
+
+impl Reader {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut attempt = 1;
+        let segments = loop {
+            match open_segments(path.as_ref()) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound && attempt < OPEN_ATTEMPTS => {
+                    attempt += 1
+                }
+                res => break res?,
+            }
+        };
+
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            segments,
+            current: None,
+        })
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = &PathBuf> {
+        self.segments.iter().map(|(segment, _)| segment)
+    }
+}
+
+fn open_segments(path: &Path) -> io::Result<VecDeque<(PathBuf, File)>> {
+    segments::list(path)?
+        .into_iter()
+        .map(|segment| File::open(&segment).map(|file| (segment, file)))
+        .collect()
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(current) = self.current.as_mut() {
+                match current.read(buf)? {
+                    0 if !buf.is_empty() => self.current = None,
+                    n => return Ok(n),
+                }
+            }
+
+            self.current = match self.segments.pop_front() {
+                // The current log file is never compressed, whatever its name.
+                Some((segment, file)) if segment == self.path => Some(Box::new(file)),
+                Some((segment, file)) => Some(compress::decoder(&segment, file)?),
+                None => return Ok(0),
+            };
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RawRecord {
+    pub time: DateTime<Utc>,
+    pub stream: &'static str,
+    pub payload: Vec<u8>,
+}
+
+// Decodes a log written in the raw format (see format::Raw).
+pub struct RawRecords<R> {
+    inner: R,
+}
+
+impl<R: Read> RawRecords<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Read> Iterator for RawRecords<R> {
+    type Item = io::Result<RawRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut header = [0; format::RAW_HEADER_SIZE];
+        match read_full(&mut self.inner, &mut header) {
+            Ok(0) => return None,
+            Ok(n) if n < header.len() => return Some(Err(truncated())),
+            Ok(_) => (),
+            Err(err) => return Some(Err(err)),
+        }
+
+        let nanos = u64::from_be_bytes(header[..8].try_into().unwrap());
+        let stream = match header[8] {
+            format::RAW_STDOUT => "stdout",
+            format::RAW_STDERR => "stderr",
+            _ => {
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown stream in raw log record",
+                )))
+            }
+        };
+        let len = u32::from_be_bytes(header[9..].try_into().unwrap()) as usize;
+
+        let mut payload = vec![0; len];
+        match read_full(&mut self.inner, &mut payload) {
+            Ok(n) if n < len => Some(Err(truncated())),
+            Ok(_) => Some(Ok(RawRecord {
+                time: Utc.timestamp_nanos(nanos as i64),
+                stream,
+                payload,
+            })),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+// Like read_exact(), but tells a clean EOF (0) from a truncated record.
+fn read_full<R: Read>(inner: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut nread = 0;
+    while nread < buf.len() {
+        match inner.read(&mut buf[nread..]) {
+            Ok(0) => break,
+            Ok(n) => nread += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(nread)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "truncated raw log record")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use format::Formatter;
+
+    #[test]
+    fn raw_records() {
+        let time = Utc.timestamp_nanos(1_700_000_000_123_456_789);
+        let mut log = Vec::new();
+        format::Raw.format(&mut log, &time, "stdout", b"hello", true);
+        format::Raw.format(&mut log, &time, "stderr", b"\x00\xff", false);
+        format::Raw.format(&mut log, &time, "stdout", b"", true);
+
+        let records: Vec<RawRecord> = RawRecords::new(&log[..])
+            .collect::<io::Result<_>>()
+            .unwrap();
+        let decoded: Vec<(&str, &[u8])> =
+            records.iter().map(|r| (r.stream, &r.payload[..])).collect();
+        assert_eq!(
+            decoded,
+            [
+                ("stdout", &b"hello\n"[..]),
+                ("stderr", b"\x00\xff"),
+                ("stdout", b"\n"),
+            ]
+        );
+        assert!(records.iter().all(|r| r.time == time));
+    }
+
+    #[test]
+    fn broken_raw_records() {
+        let mut log = Vec::new();
+        format::Raw.format(&mut log, &Utc::now(), "stdout", b"hello", true);
+
+        for len in [3, format::RAW_HEADER_SIZE + 2] {
+            let mut records = RawRecords::new(&log[..len]);
+            let err = records.next().unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        }
+
+        log[8] = 3;
+        let err = RawRecords::new(&log[..]).next().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        assert!(RawRecords::new(&b""[..]).next().is_none());
+    }
+
+    #[test]
+    fn rotation_after_open() {
+        let dir = std::env::temp_dir().join(format!("shimmy-test-reader-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("log");
+        std::fs::write(segments::numbered(&path, 1, ""), "one\n").unwrap();
+        std::fs::write(&path, "two\n").unwrap();
+
+        let mut reader = Reader::open(&path).unwrap();
+        segments::shift(&path, 3).unwrap();
+        std::fs::rename(&path, segments::numbered(&path, 1, "")).unwrap();
+        std::fs::write(&path, "three\n").unwrap();
+
+        let mut log = String::new();
+        reader.read_to_string(&mut log).unwrap();
+        assert_eq!(log, "one\ntwo\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
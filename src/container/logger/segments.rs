@@ -0,0 +1,194 @@
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Rotated log segments are named <path>.1 (the newest), <path>.2, ...,
+// with an extension if compressed (<path>.1.gz, <path>.2.zst). Segments
+// waiting for compression are <path>.rotated-<seq>, they are newer than
+// any numbered one.
+pub const EXTENSIONS: &[&str] = &["", ".gz", ".zst"];
+
+const STAGED_PREFIX: &str = "rotated-";
+
+pub fn numbered(path: &Path, n: usize, extension: &str) -> PathBuf {
+    with_suffix(path, &format!(".{}{}", n, extension))
+}
+
+pub fn staged(path: &Path, seq: u128) -> PathBuf {
+    with_suffix(path, &format!(".{}{}", STAGED_PREFIX, seq))
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+// Makes room for a new <path>.1: <path>.N becomes <path>.N+1, keeping the
+// extension, and the segments that would be over max_files (counting the
+// current log file) are removed.
+pub fn shift(path: &Path, max_files: usize) -> io::Result<()> {
+    for n in (1..max_files).rev() {
+        for extension in EXTENSIONS {
+            let segment = numbered(path, n, extension);
+            let res = if n + 1 >= max_files {
+                fs::remove_file(&segment)
+            } else {
+                fs::rename(&segment, numbered(path, n + 1, extension))
+            };
+            match res {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => (),
+            }
+        }
+    }
+    Ok(())
+}
+
+// All the segments of the log, oldest first, the current file being the last.
+pub fn list(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => format!("{}.", name),
+        None => return Err(io::ErrorKind::InvalidInput.into()),
+    };
+
+    let mut numbered = Vec::new();
+    let mut staged = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let suffix = match name.to_str().and_then(|name| name.strip_prefix(&prefix)) {
+            Some(suffix) => suffix,
+            None => continue,
+        };
+
+        if let Some(seq) = suffix.strip_prefix(STAGED_PREFIX) {
+            if let Ok(seq) = seq.parse::<u128>() {
+                staged.push((seq, entry.path()));
+            }
+        } else {
+            let n = suffix.split('.').next().unwrap();
+            let extension = &suffix[n.len()..];
+            if let (Ok(n), true) = (n.parse::<usize>(), EXTENSIONS.contains(&extension)) {
+                numbered.push((n, entry.path()));
+            }
+        }
+    }
+
+    numbered.sort_by_key(|(n, _)| Reverse(*n));
+    staged.sort_by_key(|(seq, _)| *seq);
+
+    let mut segments: Vec<PathBuf> = numbered
+        .into_iter()
+        .map(|(_, path)| path)
+        .chain(staged.into_iter().map(|(_, path)| path))
+        .collect();
+    if path.exists() {
+        segments.push(path.to_path_buf());
+    }
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "shimmy-test-segments-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn create(&self, name: &str) {
+            fs::write(self.0.join(name), name).unwrap();
+        }
+
+        fn names(&self) -> Vec<String> {
+            let mut names: Vec<String> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .collect();
+            names.sort();
+            names
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn shift_segments() {
+        let dir = TempDir::new("shift");
+        let path = dir.0.join("log");
+        for name in ["log", "log.1", "log.2.gz", "log.rotated-1"] {
+            dir.create(name);
+        }
+
+        shift(&path, 3).unwrap();
+        assert_eq!(dir.names(), ["log", "log.2", "log.rotated-1"]);
+        assert_eq!(fs::read(dir.0.join("log.2")).unwrap(), b"log.1");
+
+        shift(&path, 4).unwrap();
+        assert_eq!(dir.names(), ["log", "log.3", "log.rotated-1"]);
+
+        // Segments past max_files are left for a later shift() to remove,
+        // staged ones are never touched.
+        shift(&path, 1).unwrap();
+        shift(&path, 3).unwrap();
+        assert_eq!(dir.names(), ["log", "log.3", "log.rotated-1"]);
+        shift(&path, 4).unwrap();
+        assert_eq!(dir.names(), ["log", "log.rotated-1"]);
+    }
+
+    #[test]
+    fn list_segments() {
+        let dir = TempDir::new("list");
+        let path = dir.0.join("log");
+        for name in [
+            "log",
+            "log.1.gz",
+            "log.2",
+            "log.10.zst",
+            "log.rotated-12",
+            "log.rotated-5",
+            "log.1.txt",
+            "log.x",
+            "log.rotated-x",
+            "other.1",
+        ] {
+            dir.create(name);
+        }
+
+        let names: Vec<PathBuf> = [
+            "log.10.zst",
+            "log.2",
+            "log.1.gz",
+            "log.rotated-5",
+            "log.rotated-12",
+            "log",
+        ]
+        .iter()
+        .map(|name| dir.0.join(name))
+        .collect();
+        assert_eq!(list(&path).unwrap(), names);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(list(&path).unwrap(), names[..names.len() - 1]);
+    }
+}
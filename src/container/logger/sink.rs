@@ -0,0 +1,180 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+
+use super::compress::{Compression, Compressor};
+use super::format::Formatter;
+use super::segments;
+
+pub struct Line<'a> {
+    pub data: &'a [u8],
+    // False for lines continued in the next one (split for being
+    // too long or not finished in time).
+    pub full: bool,
+}
+
+// A log destination. Receives all the lines of a container output chunk
+// at once, to let sinks batch their writes.
+pub trait Sink {
+    fn name(&self) -> &'static str;
+
+    fn write_lines(
+        &mut self,
+        time: &DateTime<Utc>,
+        stream: &'static str,
+        lines: &[Line],
+    ) -> io::Result<()>;
+
+    // Sinks holding undelivered records want retry() to be called
+    // by this time even if no new output comes.
+    fn retry_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    fn retry(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Starts over with a file that may have been moved away (e.g. by logrotate).
+    fn reopen(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    // The container output is over, last chance to deliver the rest.
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Rotates the log once it's over max_size (0 - never): the file is renamed
+// to <path>.1 (the former <path>.1 to <path>.2 and so on, up to max_files
+// files including the current one) and a new one is started. It's done
+// between writes of whole chunks, so a chunk never ends up split across files.
+// With compression, the file is only renamed to a staged segment here, the
+// rest is up to the Compressor.
+pub struct FileSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+    compressor: Option<Compressor>,
+    // Orders staged segments, unique across shim restarts.
+    rotation_seq: u128,
+    formatter: Box<dyn Formatter>,
+}
+
+impl FileSink {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        max_size: u64,
+        max_files: usize,
+        compression: Option<Compression>,
+        formatter: Box<dyn Formatter>,
+    ) -> io::Result<Self> {
+        let file = create(&path).map_err(|err| {
+            io::Error::new(err.kind(), format!("{}: {}", path.as_ref().display(), err))
+        })?;
+
+        let max_files = max_files.max(1);
+        let compressor = match compression {
+            Some(compression) if max_size > 0 && max_files > 1 => {
+                Some(Compressor::new(path.as_ref(), max_files, compression))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            file,
+            size: 0,
+            max_size,
+            max_files,
+            compressor,
+            rotation_seq: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            formatter,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(compressor) = self.compressor.as_ref() {
+            self.rotation_seq += 1;
+            let staged = segments::staged(&self.path, self.rotation_seq);
+            fs::rename(&self.path, &staged)?;
+            compressor.submit(staged);
+        } else if self.max_files > 1 {
+            segments::shift(&self.path, self.max_files)?;
+            fs::rename(&self.path, segments::numbered(&self.path, 1, ""))?;
+        }
+
+        self.file = create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Sink for FileSink {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    // Appends if the file is still there (e.g. copytruncate) and keeps
+    // writing to the old one if the new one can't be opened.
+    fn reopen(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = file;
+        Ok(())
+    }
+
+    fn write_lines(
+        &mut self,
+        time: &DateTime<Utc>,
+        stream: &'static str,
+        lines: &[Line],
+    ) -> io::Result<()> {
+        let mut records = Vec::with_capacity(lines.iter().map(|l| l.data.len() + 64).sum());
+        for line in lines {
+            self.formatter
+                .format(&mut records, time, stream, line.data, line.full);
+        }
+
+        // Failing to rotate, keep logging to the current file.
+        let rotated = if self.max_size > 0
+            && self.size > 0
+            && self.size + records.len() as u64 > self.max_size
+        {
+            self.rotate()
+        } else {
+            Ok(())
+        };
+
+        self.file.write_all(&records)?;
+        self.size += records.len() as u64;
+        rotated
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if let Some(compressor) = self.compressor.as_mut() {
+            compressor.finish();
+        }
+        Ok(())
+    }
+}
+
+fn create<P: AsRef<Path>>(path: P) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+}
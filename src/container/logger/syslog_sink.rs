@@ -0,0 +1,342 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use log::info;
+use nix::unistd::gethostname;
+use syslog::Facility;
+
+use super::sink::{Line, Sink};
+use super::{drain_queue, expand_tag, resolve, with_default_port, StreamQueue};
+
+const DEFAULT_PORT: u16 = 514;
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Bounds the data queued for a slow stream (TCP or unix stream) receiver.
+const QUEUE_MAX_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Debug)]
+pub enum Address {
+    Unix(PathBuf),
+    Udp(String),
+    Tcp(String),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    // unix:///dev/log, udp://host[:port] or tcp://host[:port]
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("://") {
+            Some(("unix", path)) if !path.is_empty() => Ok(Self::Unix(PathBuf::from(path))),
+            Some(("udp", hostport)) if !hostport.is_empty() => {
+                Ok(Self::Udp(with_default_port(hostport, DEFAULT_PORT)))
+            }
+            Some(("tcp", hostport)) if !hostport.is_empty() => {
+                Ok(Self::Tcp(with_default_port(hostport, DEFAULT_PORT)))
+            }
+            _ => Err(format!("unsupported syslog address {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Protocol {
+    Rfc3164,
+    Rfc5424,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rfc3164" => Ok(Self::Rfc3164),
+            "rfc5424" => Ok(Self::Rfc5424),
+            _ => Err(format!("unknown syslog format {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub address: Address,
+    pub protocol: Protocol,
+    pub facility: Facility,
+    // See expand_tag().
+    pub tag: String,
+    pub container_id: String,
+    pub container_name: Option<String>,
+}
+
+// Host names are resolved once, reconnects reuse the address.
+enum Target {
+    Unix(PathBuf),
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+impl Target {
+    fn resolve(address: &Address) -> io::Result<Self> {
+        match address {
+            Address::Unix(path) => Ok(Self::Unix(path.clone())),
+            Address::Udp(hostport) => Ok(Self::Udp(resolve(hostport)?)),
+            Address::Tcp(hostport) => Ok(Self::Tcp(resolve(hostport)?)),
+        }
+    }
+}
+
+enum Conn {
+    UnixDatagram(UnixDatagram),
+    UnixStream(UnixStream),
+    Udp(UdpSocket, SocketAddr),
+    Tcp(mio::net::TcpStream),
+}
+
+impl Conn {
+    // Doesn't wait for a TCP connection to be established, writes
+    // just would block until then.
+    fn open(target: &Target) -> io::Result<Self> {
+        let conn = match target {
+            Target::Unix(path) => {
+                let socket = UnixDatagram::unbound()?;
+                match socket.connect(path) {
+                    Ok(_) => Self::UnixDatagram(socket),
+                    // Some syslog daemons listen on a stream socket.
+                    Err(err) if err.raw_os_error() == Some(libc::EPROTOTYPE) => {
+                        Self::UnixStream(UnixStream::connect(path)?)
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            Target::Udp(addr) => {
+                let local: SocketAddr = if addr.is_ipv4() {
+                    ([0, 0, 0, 0], 0).into()
+                } else {
+                    ([0u16; 8], 0).into()
+                };
+                Self::Udp(UdpSocket::bind(local)?, *addr)
+            }
+            Target::Tcp(addr) => Self::Tcp(mio::net::TcpStream::connect(addr)?),
+        };
+
+        // A stuck syslog daemon must never stall the container output
+        // (mio's TCP streams are nonblocking already).
+        match &conn {
+            Self::UnixDatagram(socket) => socket.set_nonblocking(true)?,
+            Self::UnixStream(socket) => socket.set_nonblocking(true)?,
+            Self::Udp(socket, _) => socket.set_nonblocking(true)?,
+            Self::Tcp(_) => (),
+        }
+        Ok(conn)
+    }
+
+    fn is_stream(&self) -> bool {
+        matches!(self, Self::UnixStream(_) | Self::Tcp(_))
+    }
+}
+
+// Sends every container output line as a syslog message. Datagrams carry
+// one message each, TCP uses octet counting framing (RFC6587) and unix
+// stream sockets - newline-terminated messages. Messages that can't be sent
+// right away are dropped (datagrams) or queued up to QUEUE_MAX_SIZE
+// (streams, see StreamQueue); a broken connection is reestablished
+// on a later write.
+pub struct SyslogSink {
+    config: Config,
+    target: Target,
+    tag: String,
+    hostname: String,
+    conn: Option<Conn>,
+    reconnect_at: Instant,
+    queue: StreamQueue,
+}
+
+impl SyslogSink {
+    pub fn new(config: Config) -> io::Result<Self> {
+        let target = Target::resolve(&config.address).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("syslog address {:?}: {}", config.address, err),
+            )
+        })?;
+        let tag = expand_tag(
+            &config.tag,
+            &config.container_id,
+            config.container_name.as_deref(),
+        );
+
+        let hostname = gethostname()
+            .ok()
+            .and_then(|name| name.into_string().ok())
+            .unwrap_or_else(|| String::from("localhost"));
+
+        Ok(Self {
+            config,
+            target,
+            tag,
+            hostname,
+            conn: None,
+            reconnect_at: Instant::now(),
+            queue: StreamQueue::new("syslog", QUEUE_MAX_SIZE),
+        })
+    }
+
+    fn connect(&mut self) -> io::Result<()> {
+        if self.conn.is_some() || Instant::now() < self.reconnect_at {
+            return Ok(());
+        }
+
+        self.reconnect_at = Instant::now() + RECONNECT_INTERVAL;
+        self.conn = Some(Conn::open(&self.target)?);
+        info!("[shim] connecting to syslog at {:?}", self.config.address);
+        Ok(())
+    }
+
+    // Messages queued for the broken connection are lost.
+    fn disconnect(&mut self) {
+        self.conn = None;
+        self.queue.clear();
+    }
+
+    fn format(&self, out: &mut Vec<u8>, time: &DateTime<Utc>, stream: &str, line: &[u8]) {
+        // Same severities as Docker's syslog driver uses.
+        let severity = if stream == "stderr" { 3 } else { 6 };
+        let priority = self.config.facility as u8 | severity;
+
+        match self.config.protocol {
+            Protocol::Rfc3164 => {
+                let time = time.with_timezone(&Local).format("%b %e %H:%M:%S");
+                // Local daemons add the hostname themselves.
+                if let Address::Unix(_) = self.config.address {
+                    write!(out, "<{}>{} {}: ", priority, time, self.tag).unwrap();
+                } else {
+                    write!(
+                        out,
+                        "<{}>{} {} {}: ",
+                        priority, time, self.hostname, self.tag
+                    )
+                    .unwrap();
+                }
+            }
+            Protocol::Rfc5424 => {
+                write!(
+                    out,
+                    "<{}>1 {} {} {} - {} - ",
+                    priority,
+                    time.to_rfc3339_opts(SecondsFormat::Micros, true),
+                    self.hostname,
+                    self.tag,
+                    stream
+                )
+                .unwrap();
+            }
+        }
+        out.extend_from_slice(line);
+    }
+
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        let conn = match self.conn.as_mut() {
+            Some(conn) => conn,
+            None => return Ok(()),
+        };
+
+        let res = match conn {
+            Conn::UnixDatagram(socket) => socket.send(message).map(|_| ()),
+            Conn::Udp(socket, addr) => socket.send_to(message, *addr).map(|_| ()),
+            Conn::Tcp(_) => {
+                let mut framed = format!("{} ", message.len()).into_bytes();
+                framed.extend_from_slice(message);
+                self.queue.push(framed);
+                Ok(())
+            }
+            Conn::UnixStream(_) => {
+                let mut framed = message.to_vec();
+                framed.push(b'\n');
+                self.queue.push(framed);
+                Ok(())
+            }
+        };
+
+        match res {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                self.queue.drop_message();
+                Ok(())
+            }
+            res => res,
+        }
+    }
+
+    fn flush_queue(&mut self) -> io::Result<()> {
+        let res = match self.conn.as_mut() {
+            Some(Conn::UnixStream(socket)) => self.queue.write_to(socket),
+            Some(Conn::Tcp(socket)) => self.queue.write_to(socket),
+            _ => return Ok(()),
+        };
+        match res {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                // Reconnect (not too often) and start over.
+                self.disconnect();
+                Err(err)
+            }
+        }
+    }
+}
+
+impl Sink for SyslogSink {
+    fn name(&self) -> &'static str {
+        "syslog"
+    }
+
+    fn write_lines(
+        &mut self,
+        time: &DateTime<Utc>,
+        stream: &'static str,
+        lines: &[Line],
+    ) -> io::Result<()> {
+        // While disconnected, messages are lost silently (the connection
+        // failure itself is reported every RECONNECT_INTERVAL).
+        let connected = self.connect();
+        if self.conn.is_none() {
+            return connected;
+        }
+
+        let mut message = Vec::new();
+        let mut res = Ok(());
+        for line in lines {
+            message.clear();
+            self.format(&mut message, time, stream, line.data);
+            if let Err(err) = self.send(&message) {
+                res = Err(err);
+                break;
+            }
+        }
+        if res.is_err() {
+            // Reconnect (not too often) and start over.
+            self.disconnect();
+        } else if self.conn.as_ref().is_some_and(Conn::is_stream) {
+            res = self.flush_queue();
+        }
+
+        self.queue.report_dropped();
+        res
+    }
+
+    fn retry_deadline(&self) -> Option<Instant> {
+        self.queue.retry_deadline()
+    }
+
+    fn retry(&mut self) -> io::Result<()> {
+        self.flush_queue()
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        drain_queue(self, |syslog| &syslog.queue, Self::flush_queue)
+    }
+}
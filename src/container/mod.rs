@@ -1,6 +1,9 @@
+pub mod attach;
+pub mod exit;
+pub mod logger;
 pub mod server;
 
+mod control;
 mod io;
-mod logger;
 mod reactor;
 mod signal;
@@ -1,46 +1,80 @@
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::io::Write;
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::net::UnixListener;
 use std::rc::Rc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use log::{debug, error, warn};
 use mio::unix::{EventedFd, UnixReady};
 use mio::{Event, Events, Poll, PollOpt, Ready, Token};
 
+use super::attach;
+use super::control;
+use super::exit::ExitStatus;
 use super::io;
+use super::logger;
 use super::signal;
-use crate::nixtools::process::TerminationStatus;
+use crate::nixtools::console::Console;
+use crate::nixtools::process::{kill, KillResult};
 
 const TOKEN_STDOUT: Token = Token(10);
 const TOKEN_STDERR: Token = Token(20);
 const TOKEN_SIGNAL: Token = Token(30);
 const TOKEN_ATTACH: Token = Token(40);
+const TOKEN_CONTROL: Token = Token(50);
+const TOKEN_STDIN: Token = Token(60);
 const TOKEN_UNUSED: Token = Token(1000);
 
+// How long clients may delay the shim exit to receive the exit status.
+const EXIT_FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
 pub struct Reactor {
     poll: Poll,
     heartbeat: Duration,
     stdin_gatherer: Option<io::Gatherer>,
     stdin_once: bool,
+    stdin_exclusive: bool,
+    stdin_owner: Option<Token>,
+    stdin_registered: bool,
+    stdin_closing: bool,
     stdout_scatterer: Option<io::Scatterer>,
     stderr_scatterer: Option<io::Scatterer>,
+    logger: Rc<RefCell<logger::Logger>>,
+    log_writers: Vec<Rc<RefCell<logger::Writer>>>,
     signal_handler: signal::Handler,
     attach_listener: UnixListener,
-    attach_streams: HashMap<Token, RawFd>,
-    attach_last_token: Token,
+    attach_config: attach::Config,
+    attach_access: attach::Access,
+    attach_streams: HashMap<Token, Rc<RefCell<attach::Stream>>>,
+    attach_interests: HashMap<Token, Ready>,
+    control_listener: Option<UnixListener>,
+    control_streams: HashMap<Token, control::Stream>,
+    control_waiters: Vec<control::Stream>,
+    last_token: Token,
+    console: Option<Console>,
+    started_at: Instant,
+    draining: bool,
 }
 
 impl Reactor {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         heartbeat: Duration,
         stdin_gatherer: Option<io::Gatherer>,
         stdin_once: bool,
+        stdin_exclusive: bool,
         stdout_scatterer: Option<io::Scatterer>,
         stderr_scatterer: Option<io::Scatterer>,
+        logger: Rc<RefCell<logger::Logger>>,
+        log_writers: Vec<Rc<RefCell<logger::Writer>>>,
         signal_handler: signal::Handler,
         attach_listener: UnixListener,
+        attach_config: attach::Config,
+        attach_access: attach::Access,
+        control_listener: Option<UnixListener>,
+        console: Option<Console>,
     ) -> Self {
         let poll = Poll::new().expect("mio::Poll::new() failed");
 
@@ -80,26 +114,52 @@ impl Reactor {
         )
         .expect("mio::Poll::register(attach listener) failed");
 
+        if let Some(listener) = control_listener.as_ref() {
+            poll.register(
+                &EventedFd(&listener.as_raw_fd()),
+                TOKEN_CONTROL,
+                Ready::readable() | UnixReady::error(),
+                PollOpt::level(),
+            )
+            .expect("mio::Poll::register(control listener) failed");
+        }
+
         Self {
             poll: poll,
             heartbeat: heartbeat,
             stdin_gatherer: stdin_gatherer,
             stdin_once: stdin_once,
+            stdin_exclusive,
+            stdin_owner: None,
+            stdin_registered: false,
+            stdin_closing: false,
             stdout_scatterer: stdout_scatterer,
             stderr_scatterer: stderr_scatterer,
+            logger,
+            log_writers,
             signal_handler: signal_handler,
             attach_listener: attach_listener,
+            attach_config,
+            attach_access,
             attach_streams: HashMap::new(),
-            attach_last_token: TOKEN_UNUSED,
+            attach_interests: HashMap::new(),
+            control_listener,
+            control_streams: HashMap::new(),
+            control_waiters: Vec::new(),
+            last_token: TOKEN_UNUSED,
+            console: console,
+            started_at: Instant::now(),
+            draining: false,
         }
     }
 
-    pub fn run(&mut self) -> TerminationStatus {
+    pub fn run(&mut self) -> ExitStatus {
         while self.signal_handler.container_status().is_none() {
             if self.poll_once() == 0 {
                 debug!("[shim] still serving container");
             }
         }
+        let exit_status = ExitStatus::new(self.signal_handler.container_status().unwrap());
 
         // Drain stdout & stderr.
         self.poll
@@ -108,19 +168,130 @@ impl Reactor {
         self.poll
             .deregister(&EventedFd(&self.attach_listener.as_raw_fd()))
             .expect("mio::Poll::deregister(attach listener) failed");
+        self.deregister_control();
+        self.drop_stdin();
         self.heartbeat = Duration::from_millis(0);
 
+        // Attach clients get the rest of the output once the draining is over.
+        self.draining = true;
+        self.sync_attach_streams();
+
         while self.poll_once() != 0 {
             debug!("[shim] draining container IO streams");
         }
+        self.flush_log_writers(true);
 
-        self.signal_handler.container_status().unwrap()
+        exit_status
+    }
+
+    // Called once the exit status is saved. Log sinks may take a while
+    // delivering the rest of the output, so they go last.
+    pub fn finish(&mut self, exit_status: &ExitStatus) {
+        self.notify_exit(exit_status);
+        self.logger.borrow_mut().close();
+    }
+
+    // Tells attach clients and control waiters about the container exit.
+    // Clients not reading may delay the shim exit only up to
+    // EXIT_FLUSH_TIMEOUT all together.
+    fn notify_exit(&mut self, exit_status: &ExitStatus) {
+        let poll = Poll::new().expect("mio::Poll::new() failed");
+        let register = |token: Token, fd: RawFd| {
+            poll.register(
+                &EventedFd(&fd),
+                token,
+                Ready::writable() | UnixReady::error() | UnixReady::hup(),
+                PollOpt::level(),
+            )
+            .expect("mio::Poll::register(exiting client) failed");
+        };
+
+        let mut attach_streams = HashMap::new();
+        for (token, stream) in self.attach_streams.drain() {
+            let res = stream.borrow_mut().send_exit(exit_status);
+            match res {
+                Ok(_) => {
+                    register(token, stream.borrow().as_raw_fd());
+                    attach_streams.insert(token, stream);
+                }
+                Err(err) => debug!("[shim] failed to notify attach client about exit: {}", err),
+            }
+        }
+
+        let mut waiters = HashMap::new();
+        for mut waiter in std::mem::take(&mut self.control_waiters) {
+            match waiter.respond(&control::Response::Exit(exit_status)) {
+                Ok(_) => {
+                    let token = self.next_token();
+                    register(token, waiter.as_raw_fd());
+                    waiters.insert(token, waiter);
+                }
+                Err(err) => warn!("[shim] failed to notify control waiter: {}", err),
+            }
+        }
+
+        let deadline = Instant::now() + EXIT_FLUSH_TIMEOUT;
+        let mut events = Events::with_capacity(128);
+        while !attach_streams.is_empty() || !waiters.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!(
+                    "[shim] gave up notifying {} attach client(s) and {} control waiter(s) about exit",
+                    attach_streams.len(),
+                    waiters.len()
+                );
+                return;
+            }
+
+            poll.poll(&mut events, Some(deadline - now))
+                .expect("mio::Poll::poll() failed");
+            for event in events.iter() {
+                let token = event.token();
+                if let Some(stream) = attach_streams.get(&token) {
+                    let res = stream.borrow_mut().flush_exit();
+                    match res {
+                        Ok(false) => continue,
+                        Ok(true) => (),
+                        Err(err) => {
+                            debug!("[shim] failed to notify attach client about exit: {}", err)
+                        }
+                    }
+                    attach_streams.remove(&token);
+                } else if let Some(waiter) = waiters.get_mut(&token) {
+                    match waiter.flush_outbox() {
+                        Ok(_) if waiter.wants_write() => continue,
+                        Ok(_) => (),
+                        Err(err) => warn!("[shim] failed to notify control waiter: {}", err),
+                    }
+                    waiters.remove(&token);
+                }
+            }
+        }
     }
 
     fn poll_once(&mut self) -> i32 {
+        let mut timeout = if self
+            .attach_streams
+            .values()
+            .any(|stream| stream.borrow().is_handshaking())
+        {
+            self.heartbeat.min(attach::HANDSHAKE_TIMEOUT)
+        } else {
+            self.heartbeat
+        };
+        if let Some(deadline) = self
+            .log_writers
+            .iter()
+            .filter_map(|writer| writer.borrow().flush_deadline())
+            .chain(self.logger.borrow().retry_deadline())
+            .min()
+        {
+            timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+        }
+
         let mut events = Events::with_capacity(128);
         self.poll
-            .poll(&mut events, Some(self.heartbeat))
+            .poll(&mut events, Some(timeout))
             .expect("mio::Poll::poll() failed");
 
         let mut event_count = 0;
@@ -129,14 +300,58 @@ impl Reactor {
             match event.token() {
                 TOKEN_STDOUT => self.handle_stdout_event(event),
                 TOKEN_STDERR => self.handle_stderr_event(event),
-                TOKEN_SIGNAL => self.signal_handler.handle_signal(),
+                TOKEN_SIGNAL => self.handle_signal_event(),
                 TOKEN_ATTACH => self.handle_attach_listener_event(event),
+                TOKEN_CONTROL => self.handle_control_listener_event(event),
+                TOKEN_STDIN => self.handle_stdin_event(event),
+                token if self.control_streams.contains_key(&token) => {
+                    self.handle_control_stream_event(event)
+                }
                 _ => self.handle_attach_stream_event(event),
             }
         }
+
+        self.flush_log_writers(false);
+        self.logger.borrow_mut().retry_expired();
+        self.sync_stdin_gatherer();
+        self.sync_attach_streams();
         event_count
     }
 
+    // Incomplete lines are logged once they have waited for too long,
+    // or unconditionally when the container output is over.
+    fn flush_log_writers(&mut self, all: bool) {
+        for writer in self.log_writers.iter() {
+            let mut writer = writer.borrow_mut();
+            let res = if all {
+                writer.flush()
+            } else {
+                writer.flush_expired()
+            };
+            if let Err(err) = res {
+                error!("[shim] failed to flush container log: {}", err);
+            }
+        }
+    }
+
+    fn handle_signal_event(&mut self) {
+        match self.signal_handler.handle_signal() {
+            Some(signal::Action::ReopenLogs) => {
+                if let Err(err) = self.reopen_logs() {
+                    error!("[shim] failed to reopen container logs: {}", err);
+                }
+            }
+            None => (),
+        }
+    }
+
+    // Lines already handed to the logger are written before the reopening,
+    // incomplete ones stay buffered and go to the new files.
+    fn reopen_logs(&mut self) -> std::io::Result<()> {
+        debug!("[shim] reopening container logs");
+        self.logger.borrow_mut().reopen()
+    }
+
     fn handle_stdout_event(&mut self, event: Event) {
         if self.stdout_scatterer.is_none() {
             warn!("[shim] dubious, got event on already closed STDOUT");
@@ -187,6 +402,31 @@ impl Reactor {
         }
     }
 
+    fn handle_stdin_event(&mut self, event: Event) {
+        if self.stdin_gatherer.is_none() {
+            warn!("[shim] dubious, got event on already closed STDIN");
+            return;
+        }
+
+        let readiness = UnixReady::from(event.readiness());
+        if readiness.is_error() {
+            // The reading end is gone, nobody is ever going to consume the data.
+            error!("[shim] container's STDIN error, closing it");
+            self.drop_stdin();
+            return;
+        }
+
+        if readiness.is_writable() {
+            match self.stdin_gatherer.as_mut().unwrap().flush() {
+                Ok(nbytes) => debug!("[shim] flushed {} byte(s) to container's STDIN", nbytes),
+                Err(err) => {
+                    error!("[shim] write to container's STDIN failed: {}", err);
+                    self.drop_stdin();
+                }
+            }
+        }
+    }
+
     fn handle_attach_listener_event(&mut self, event: Event) {
         if UnixReady::from(event.readiness()).is_error() {
             match self.attach_listener.take_error() {
@@ -199,17 +439,30 @@ impl Reactor {
 
         match self.attach_listener.accept() {
             Ok((stream, _)) => {
-                debug!("[shim] new attach socket stream");
-                let token = self.register_attach_stream(stream.as_raw_fd());
-                let stream_rc: Rc<RefCell<UnixStream>> = Rc::new(RefCell::new(stream));
-                if let Some(ref mut stdin_gatherer) = self.stdin_gatherer {
-                    stdin_gatherer.add_source(token, stream_rc.clone());
+                if let Err(reason) = self.attach_access.authorize(&stream) {
+                    warn!("[shim] rejected attach socket stream: {}", reason);
+                    return;
                 }
+
+                debug!("[shim] new attach socket stream");
+                // Taken right away, so that a replay never misses output
+                // coming during the handshake.
+                let history = [&self.stdout_scatterer, &self.stderr_scatterer]
+                    .iter()
+                    .filter_map(|s| s.as_ref().and_then(|s| s.history(usize::MAX)))
+                    .collect();
+                let stream = Rc::new(RefCell::new(attach::Stream::new(
+                    stream,
+                    self.attach_config.clone(),
+                    history,
+                )));
+                self.register_attach_stream(stream.clone());
+
                 if let Some(ref mut stdout_scatterer) = self.stdout_scatterer {
-                    stdout_scatterer.add_sink(stream_rc.clone());
+                    stdout_scatterer.add_sink(stream.clone());
                 }
                 if let Some(ref mut stderr_scatterer) = self.stderr_scatterer {
-                    stderr_scatterer.add_sink(stream_rc.clone());
+                    stderr_scatterer.add_sink(stream);
                 }
             }
             Err(err) => error!("[shim] attach listener accept failed: {}", err),
@@ -217,41 +470,310 @@ impl Reactor {
     }
 
     fn handle_attach_stream_event(&mut self, event: Event) {
-        if self.stdin_gatherer.is_none() {
-            warn!("[shim] container's STDIN has been already closed");
+        let token = event.token();
+        let stream = match self.attach_streams.get(&token) {
+            Some(stream) => stream.clone(),
+            None => {
+                warn!(
+                    "[shim] dubious, cannot find attach stream for token {:?}",
+                    token
+                );
+                return;
+            }
+        };
+
+        let readiness = UnixReady::from(event.readiness());
+        if readiness.is_error() {
+            // E.g. the client has gone leaving some output unread.
+            error!("[shim] attach socket stream error");
+            self.handle_attach_stdin_eof(token, &stream);
+            self.deregister_attach_stream(token);
             return;
         }
 
-        let stdin_gatherer = self.stdin_gatherer.as_mut().unwrap();
-        if event.readiness().is_readable() {
-            match stdin_gatherer.gather(event.token()) {
-                Ok(nbytes) => {
-                    debug!("[shim] gathered {} byte(s) to container's STDIN", nbytes);
-                    if nbytes == 0 {
+        if readiness.is_writable() {
+            if let Err(err) = stream.borrow_mut().flush_outbox() {
+                error!("[shim] attach socket stream write error: {}", err);
+                self.deregister_attach_stream(token);
+                return;
+            }
+        }
+
+        if readiness.is_readable() {
+            let res = stream.borrow_mut().receive();
+            let messages = match res {
+                Ok(messages) => messages,
+                Err(err) => {
+                    error!("[shim] attach socket stream read error: {}", err);
+                    // Before the deregistration releases the STDIN ownership.
+                    self.handle_attach_stdin_eof(token, &stream);
+                    self.deregister_attach_stream(token);
+                    return;
+                }
+            };
+
+            for message in messages {
+                // Some messages (HELLO, detach keys) may end the stream.
+                if !self.attach_streams.contains_key(&token) {
+                    break;
+                }
+
+                match message {
+                    attach::Message::Hello(hello) => {
+                        self.handle_attach_hello(token, &stream, hello)
+                    }
+                    attach::Message::Data(data) => self.handle_attach_stdin_data(token, &data),
+                    attach::Message::Detach => self.detach_attach_stream(token),
+                    attach::Message::Resize { rows, cols } => self.resize_console(rows, cols),
+                    attach::Message::StdinEof => {
+                        debug!("[shim] attach socket stream closed its STDIN");
+                        self.handle_attach_stdin_eof(token, &stream);
+                    }
+                    attach::Message::Eof => {
+                        // Half-closed clients still receive container's output.
                         debug!("[shim] attach socket stream eof");
-                        stdin_gatherer.remove_source(event.token());
-                        self.deregister_attach_stream(event.token());
-                        if self.stdin_once {
-                            self.stdin_gatherer = None;
-                        }
+                        self.handle_attach_stdin_eof(token, &stream);
                     }
                 }
-                Err(io::Error::Source(err)) => {
-                    error!("[shim] attach socket stream read error: {}", err);
-                    stdin_gatherer.remove_source(event.token());
-                    self.deregister_attach_stream(event.token());
-                    if self.stdin_once {
-                        self.stdin_gatherer = None;
+            }
+        } else if readiness.is_hup() {
+            debug!("[shim] attach socket stream HUP");
+            self.deregister_attach_stream(token);
+        }
+    }
+
+    fn handle_attach_hello(
+        &mut self,
+        token: Token,
+        stream: &Rc<RefCell<attach::Stream>>,
+        hello: attach::Hello,
+    ) {
+        let replay = match hello.replay.as_ref().map(|r| r.to_limit()) {
+            Some(Some(limit)) => Some(limit),
+            Some(None) => {
+                warn!("[shim] attach client requested malformed replay, ignoring");
+                None
+            }
+            None => None,
+        };
+
+        if hello.takeover && !hello.readonly {
+            if self.stdin_exclusive {
+                debug!(
+                    "[shim] attach stream {:?} takes over container's STDIN",
+                    token
+                );
+                self.stdin_owner = Some(token);
+            } else {
+                debug!("[shim] STDIN takeover requested, but STDIN isn't exclusive");
+            }
+        }
+
+        if let Err(err) = stream.borrow_mut().start(replay) {
+            error!("[shim] attach socket stream write error: {}", err);
+            self.deregister_attach_stream(token);
+        }
+    }
+
+    fn handle_attach_stdin_data(&mut self, token: Token, data: &[u8]) {
+        if !self.claim_stdin(token) {
+            debug!("[shim] container's STDIN is owned by another attach stream, dropping data");
+            return;
+        }
+
+        if self.stdin_closing {
+            debug!("[shim] container's STDIN is closing, dropping attach stream data");
+            return;
+        }
+
+        match self.stdin_gatherer.as_mut() {
+            Some(stdin_gatherer) => match stdin_gatherer.gather(data) {
+                Ok(nbytes) => debug!("[shim] gathered {} byte(s) to container's STDIN", nbytes),
+                Err(err) => {
+                    error!("[shim] write to container's STDIN failed: {}", err);
+                    self.drop_stdin();
+                }
+            },
+            None => debug!("[shim] container's STDIN is closed, dropping attach stream data"),
+        }
+    }
+
+    // In the exclusive mode only the owner's EOF counts, and it also
+    // releases the ownership.
+    fn handle_attach_stdin_eof(&mut self, token: Token, stream: &Rc<RefCell<attach::Stream>>) {
+        if stream.borrow().is_readonly() {
+            return;
+        }
+        if self.stdin_exclusive {
+            if self.stdin_owner != Some(token) {
+                return;
+            }
+            self.stdin_owner = None;
+        }
+
+        if self.stdin_once {
+            self.close_stdin();
+        }
+    }
+
+    // First come, first served, unless taken over explicitly via HELLO.
+    fn claim_stdin(&mut self, token: Token) -> bool {
+        if !self.stdin_exclusive {
+            return true;
+        }
+
+        match self.stdin_owner {
+            Some(owner) => owner == token,
+            None => {
+                debug!("[shim] attach stream {:?} owns container's STDIN", token);
+                self.stdin_owner = Some(token);
+                true
+            }
+        }
+    }
+
+    fn resize_console(&mut self, rows: u16, cols: u16) {
+        match self.console.as_ref() {
+            Some(console) => {
+                debug!("[shim] resizing container's console to {}x{}", cols, rows);
+                if let Err(err) = console.resize(rows, cols) {
+                    error!("[shim] console resize failed: {}", err);
+                }
+            }
+            None => warn!("[shim] dubious, resize request without container's console"),
+        }
+    }
+
+    fn handle_control_listener_event(&mut self, event: Event) {
+        let listener = self.control_listener.as_ref().unwrap();
+        if UnixReady::from(event.readiness()).is_error() {
+            match listener.take_error() {
+                Ok(None) => error!("[shim] control listener event with error flag"),
+                Ok(Some(err)) => error!("[shim] control listener error: {}", err),
+                Err(err) => error!("[shim] control listener take_error() failed: {}", err),
+            }
+            return;
+        }
+
+        match listener.accept() {
+            Ok((stream, _)) => {
+                debug!("[shim] new control socket stream");
+                let token = self.next_token();
+                self.poll
+                    .register(
+                        &EventedFd(&stream.as_raw_fd()),
+                        token,
+                        Ready::readable() | UnixReady::error() | UnixReady::hup(),
+                        PollOpt::level(),
+                    )
+                    .expect("mio::Poll::register(control stream) failed");
+                self.control_streams
+                    .insert(token, control::Stream::new(stream));
+            }
+            Err(err) => error!("[shim] control listener accept failed: {}", err),
+        }
+    }
+
+    fn handle_control_stream_event(&mut self, event: Event) {
+        let token = event.token();
+        let readiness = UnixReady::from(event.readiness());
+        if readiness.is_error() {
+            error!("[shim] control socket stream error");
+            self.deregister_control_stream(token);
+            return;
+        }
+
+        if readiness.is_writable() {
+            if let Err(err) = self.control_streams.get_mut(&token).unwrap().flush_outbox() {
+                error!("[shim] control socket stream write error: {}", err);
+                self.deregister_control_stream(token);
+                return;
+            }
+        }
+
+        if readiness.is_readable() {
+            self.handle_control_requests(token);
+        } else if !readiness.is_writable() {
+            debug!("[shim] control socket stream HUP");
+            self.deregister_control_stream(token);
+            return;
+        }
+        self.sync_control_stream(token);
+    }
+
+    fn handle_control_requests(&mut self, token: Token) {
+        let requests = match self.control_streams.get_mut(&token).unwrap().receive() {
+            Ok(Some(requests)) => requests,
+            Ok(None) => {
+                debug!("[shim] control socket stream eof");
+                self.deregister_control_stream(token);
+                return;
+            }
+            Err(err) => {
+                error!("[shim] control socket stream read error: {}", err);
+                self.deregister_control_stream(token);
+                return;
+            }
+        };
+
+        for request in requests {
+            let response = match request {
+                Ok(control::Request::Status) => control::Response::Status {
+                    container_pid: self.signal_handler.container_pid().as_raw(),
+                    uptime_secs: self.started_at.elapsed().as_secs(),
+                    attached_clients: self.attach_streams.len(),
+                    stdin_open: self.stdin_gatherer.is_some() && !self.stdin_closing,
+                },
+                Ok(control::Request::Kill { signal }) => self.kill_container(&signal),
+                Ok(control::Request::CloseStdin) => {
+                    debug!("[shim] closing container's STDIN on control request");
+                    self.close_stdin();
+                    control::Response::Ok
+                }
+                Ok(control::Request::ReopenLogs) => match self.reopen_logs() {
+                    Ok(_) => control::Response::Ok,
+                    Err(err) => control::Response::Error {
+                        message: err.to_string(),
+                    },
+                },
+                Ok(control::Request::Wait) => {
+                    // Long-poll: the response is sent once the container exits.
+                    if let Some(stream) = self.deregister_control_stream(token) {
+                        self.control_waiters.push(stream);
                     }
+                    return;
                 }
-                Err(io::Error::Sink(err)) => {
-                    error!("[shim] write to container's STDIN failed: {}", err);
+                Err(message) => control::Response::Error { message },
+            };
+
+            let stream = self.control_streams.get_mut(&token).unwrap();
+            if let Err(err) = stream.respond(&response) {
+                error!("[shim] control socket stream write error: {}", err);
+                self.deregister_control_stream(token);
+                return;
+            }
+        }
+    }
+
+    fn kill_container(&self, signal: &control::SignalSpec) -> control::Response<'static> {
+        let signal = match signal.to_signal() {
+            Some(signal) => signal,
+            None => {
+                return control::Response::Error {
+                    message: "unknown signal".into(),
                 }
             }
-        } else if UnixReady::from(event.readiness()).is_hup() {
-            debug!("[shim] attach socket stream HUP");
-            stdin_gatherer.remove_source(event.token());
-            self.deregister_attach_stream(event.token());
+        };
+
+        debug!("[shim] sending {} to container on control request", signal);
+        match kill(self.signal_handler.container_pid(), signal) {
+            Ok(KillResult::Delivered) => control::Response::Ok,
+            Ok(KillResult::ProcessNotFound) => control::Response::Error {
+                message: "container process not found".into(),
+            },
+            Err(err) => control::Response::Error {
+                message: err.to_string(),
+            },
         }
     }
 
@@ -269,30 +791,240 @@ impl Reactor {
         self.stderr_scatterer = None;
     }
 
-    fn register_attach_stream(&mut self, fd: RawFd) -> Token {
-        self.attach_last_token = Token(usize::from(self.attach_last_token) + 1);
+    // Pending data is still delivered to the container before its STDIN
+    // is actually closed, see sync_stdin_gatherer().
+    fn close_stdin(&mut self) {
+        match self.stdin_gatherer.as_ref() {
+            Some(stdin_gatherer) if stdin_gatherer.has_pending() => self.stdin_closing = true,
+            Some(_) => self.end_stdin(),
+            None => (),
+        }
+    }
 
-        self.poll
-            .register(
-                &EventedFd(&fd),
-                self.attach_last_token,
-                Ready::readable() | UnixReady::error() | UnixReady::hup(),
-                PollOpt::level(),
-            )
-            .expect("mio::Poll::register(attach stream) failed");
+    fn end_stdin(&mut self) {
+        self.drop_stdin();
+        if let Some(console) = self.console.as_ref() {
+            if let Err(err) = console.send_eof() {
+                warn!("[shim] couldn't signal EOF to container's console: {}", err);
+            }
+        }
+    }
 
-        self.attach_streams.insert(self.attach_last_token, fd);
+    fn drop_stdin(&mut self) {
+        let stdin_gatherer = match self.stdin_gatherer.take() {
+            Some(stdin_gatherer) => stdin_gatherer,
+            None => return,
+        };
 
-        self.attach_last_token
+        if stdin_gatherer.has_pending() {
+            warn!("[shim] closing container's STDIN with unwritten data");
+        }
+        if self.stdin_registered {
+            self.poll
+                .deregister(&stdin_gatherer)
+                .expect("mio::Poll::deregister(container STDIN) failed");
+            self.stdin_registered = false;
+        }
+        self.stdin_closing = false;
     }
 
-    fn deregister_attach_stream(&mut self, token: Token) {
-        if let Some(fd) = self.attach_streams.remove(&token) {
+    // Container's STDIN is polled only while there is data waiting for it.
+    fn sync_stdin_gatherer(&mut self) {
+        let stdin_gatherer = match self.stdin_gatherer.as_ref() {
+            Some(stdin_gatherer) => stdin_gatherer,
+            None => return,
+        };
+
+        if self.stdin_closing && !stdin_gatherer.has_pending() {
+            debug!("[shim] container's STDIN drained, closing it");
+            self.end_stdin();
+            return;
+        }
+
+        if stdin_gatherer.has_pending() && !self.stdin_registered {
+            self.poll
+                .register(
+                    stdin_gatherer,
+                    TOKEN_STDIN,
+                    Ready::writable() | UnixReady::error(),
+                    PollOpt::level(),
+                )
+                .expect("mio::Poll::register(container STDIN) failed");
+            self.stdin_registered = true;
+        } else if !stdin_gatherer.has_pending() && self.stdin_registered {
             self.poll
-                .deregister(&EventedFd(&fd))
-                .expect("mio::Poll::deregister(attach conn) failed");
+                .deregister(stdin_gatherer)
+                .expect("mio::Poll::deregister(container STDIN) failed");
+            self.stdin_registered = false;
+        }
+    }
+
+    fn next_token(&mut self) -> Token {
+        self.last_token = Token(usize::from(self.last_token) + 1);
+        self.last_token
+    }
+
+    fn register_attach_stream(&mut self, stream: Rc<RefCell<attach::Stream>>) -> Token {
+        // Polling is set up by sync_attach_streams() according to stream needs.
+        let token = self.next_token();
+        self.attach_streams.insert(token, stream);
+        token
+    }
+
+    // Unlike a disconnect, detaching never closes container's STDIN.
+    fn detach_attach_stream(&mut self, token: Token) {
+        debug!("[shim] attach stream {:?} detached", token);
+        if let Some(stream) = self.attach_streams.get(&token) {
+            stream.borrow_mut().detach();
+        }
+        self.deregister_attach_stream(token);
+    }
+
+    fn deregister_attach_stream(&mut self, token: Token) {
+        if self.stdin_owner == Some(token) {
+            debug!(
+                "[shim] attach stream {:?} released container's STDIN",
+                token
+            );
+            self.stdin_owner = None;
+        }
+
+        if let Some(stream) = self.attach_streams.remove(&token) {
+            if self.attach_interests.remove(&token).is_some() {
+                self.poll
+                    .deregister(&EventedFd(&stream.borrow().as_raw_fd()))
+                    .expect("mio::Poll::deregister(attach conn) failed");
+            }
         } else {
             warn!("[shim] attach stream with token {:?} not found", token);
         }
     }
+
+    // Expires handshakes, drops closed streams, and (re)registers the rest
+    // for reading and/or writing depending on whether they have input left
+    // and output queued.
+    fn sync_attach_streams(&mut self) {
+        let mut closed = Vec::new();
+        for (token, stream) in self.attach_streams.iter() {
+            let mut stream = stream.borrow_mut();
+            if let Err(err) = stream.expire_handshake() {
+                error!("[shim] attach socket stream write error: {}", err);
+                closed.push(*token);
+            } else if stream.is_closed() {
+                closed.push(*token);
+            }
+        }
+        for token in closed {
+            debug!("[shim] dropping closed attach stream {:?}", token);
+            self.deregister_attach_stream(token);
+        }
+
+        // Clients aren't read while the container doesn't keep up with its STDIN.
+        // Handshakes are still served to not misdetect the protocol.
+        let stdin_full = self
+            .stdin_gatherer
+            .as_ref()
+            .is_some_and(|stdin_gatherer| stdin_gatherer.is_full());
+
+        for (token, stream) in self.attach_streams.iter() {
+            let stream = stream.borrow();
+            // None - not polled at all. Clients done with sending are still
+            // polled (with no interest) for HUP to drop them once gone for good.
+            let interest = if self.draining {
+                None
+            } else {
+                let mut interest = Ready::empty();
+                if stream.wants_read() && (!stdin_full || stream.is_handshaking()) {
+                    interest |= Ready::readable();
+                }
+                if stream.wants_write() {
+                    interest |= Ready::writable();
+                }
+                if interest.is_empty() && stream.wants_read() {
+                    None
+                } else {
+                    Some(interest)
+                }
+            };
+
+            let fd = stream.as_raw_fd();
+            match (self.attach_interests.get(token), interest) {
+                (Some(current), Some(interest)) if *current == interest => (),
+                (Some(_), None) => {
+                    self.poll
+                        .deregister(&EventedFd(&fd))
+                        .expect("mio::Poll::deregister(attach stream) failed");
+                    self.attach_interests.remove(token);
+                }
+                (Some(_), Some(interest)) => {
+                    self.poll
+                        .reregister(
+                            &EventedFd(&fd),
+                            *token,
+                            interest | UnixReady::error() | UnixReady::hup(),
+                            PollOpt::level(),
+                        )
+                        .expect("mio::Poll::reregister(attach stream) failed");
+                    self.attach_interests.insert(*token, interest);
+                }
+                (None, None) => (),
+                (None, Some(interest)) => {
+                    self.poll
+                        .register(
+                            &EventedFd(&fd),
+                            *token,
+                            interest | UnixReady::error() | UnixReady::hup(),
+                            PollOpt::level(),
+                        )
+                        .expect("mio::Poll::register(attach stream) failed");
+                    self.attach_interests.insert(*token, interest);
+                }
+            }
+        }
+    }
+
+    // Clients are read again only once they've received all the replies.
+    fn sync_control_stream(&mut self, token: Token) {
+        let stream = match self.control_streams.get(&token) {
+            Some(stream) => stream,
+            None => return,
+        };
+
+        let interest = if stream.wants_write() {
+            Ready::writable()
+        } else {
+            Ready::readable()
+        };
+        self.poll
+            .reregister(
+                &EventedFd(&stream.as_raw_fd()),
+                token,
+                interest | UnixReady::error() | UnixReady::hup(),
+                PollOpt::level(),
+            )
+            .expect("mio::Poll::reregister(control stream) failed");
+    }
+
+    fn deregister_control_stream(&mut self, token: Token) -> Option<control::Stream> {
+        let stream = self.control_streams.remove(&token);
+        if let Some(stream) = stream.as_ref() {
+            self.poll
+                .deregister(&EventedFd(&stream.as_raw_fd()))
+                .expect("mio::Poll::deregister(control stream) failed");
+        }
+        stream
+    }
+
+    fn deregister_control(&mut self) {
+        if let Some(listener) = self.control_listener.as_ref() {
+            self.poll
+                .deregister(&EventedFd(&listener.as_raw_fd()))
+                .expect("mio::Poll::deregister(control listener) failed");
+        }
+
+        let tokens: Vec<Token> = self.control_streams.keys().cloned().collect();
+        for token in tokens {
+            self.deregister_control_stream(token);
+        }
+    }
 }
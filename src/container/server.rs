@@ -1,18 +1,21 @@
 use std::cell::RefCell;
-use std::os::unix::net::UnixListener;
 use std::path::Path;
 use std::rc::Rc;
 use std::time::Duration;
 
 use log::debug;
+use nix::sys::stat::Mode;
 use nix::unistd::Pid;
 
-use crate::nixtools::process::TerminationStatus;
+use crate::nixtools::console::Console;
 use crate::nixtools::signal::Signalfd;
+use crate::nixtools::socket::{bind_unix_listener, bind_unix_listener_masked};
 use crate::nixtools::stdio::{IStream, OStream};
 
+use super::attach;
+use super::exit::ExitStatus;
 use super::io;
-use super::logger::{Logger, Writer};
+use super::logger::{self, Logger, Writer};
 use super::reactor::Reactor;
 use super::signal;
 
@@ -21,34 +24,80 @@ pub struct Server {
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn new<P: AsRef<Path>>(
         container_pid: Pid,
         container_attachfile: P,
-        container_logfile: P,
+        container_controlfile: Option<P>,
+        (container_stdout_logfile, container_stderr_logfile): (Option<P>, Option<P>),
+        log_config: logger::Config,
         (container_stdin, container_stdout, container_stderr): (
             Option<OStream>,
             Option<IStream>,
             Option<IStream>,
         ),
+        container_console: Option<Console>,
         stdin_once: bool,
+        stdin_exclusive: bool,
+        attach_replay_size: usize,
+        attach_config: attach::Config,
+        attach_access: attach::Access,
         sigfd: Signalfd,
-    ) -> Self {
-        let attach_listener = UnixListener::bind(container_attachfile).unwrap();
+    ) -> Result<Self, String> {
+        // Nobody but the shim can connect until the ownership and mode are set.
+        let (attach_listener, umask) =
+            bind_unix_listener_masked(&container_attachfile, Mode::from_bits_truncate(0o177))
+                .map_err(|err| {
+                    format!(
+                        "bind() of attach socket {} failed: {}",
+                        container_attachfile.as_ref().display(),
+                        err
+                    )
+                })?;
         attach_listener
             .set_nonblocking(true)
             .expect("Couldn't set attach listener nonblocking");
+        attach_access
+            .apply(&container_attachfile, umask)
+            .map_err(|err| format!("setting attach socket mode or ownership failed: {}", err))?;
 
-        let logger = Rc::new(RefCell::new(Logger::new(container_logfile)));
+        let control_listener = match container_controlfile {
+            Some(path) => {
+                let listener = bind_unix_listener(&path).map_err(|err| {
+                    format!(
+                        "bind() of control socket {} failed: {}",
+                        path.as_ref().display(),
+                        err
+                    )
+                })?;
+                listener
+                    .set_nonblocking(true)
+                    .expect("Couldn't set control listener nonblocking");
+                Some(listener)
+            }
+            None => None,
+        };
 
         let stdin_gatherer = match container_stdin {
             Some(stream) => Some(io::Gatherer::new(stream)),
             None => None,
         };
 
+        let logger = Logger::new(
+            (container_stdout_logfile, container_stderr_logfile),
+            log_config,
+        )
+        .map_err(|err| format!("container logger setup failed: {}", err))?;
+        let logger = Rc::new(RefCell::new(logger));
+
+        let mut log_writers = Vec::new();
+
         let stdout_scatterer = match container_stdout {
             Some(stream) => {
-                let mut scatterer = io::Scatterer::stdout(stream);
-                scatterer.add_sink(Rc::new(RefCell::new(Writer::stdout(logger.clone()))));
+                let writer = Rc::new(RefCell::new(Writer::stdout(logger.clone())));
+                let mut scatterer = io::Scatterer::stdout(stream, attach_replay_size);
+                scatterer.add_sink(writer.clone());
+                log_writers.push(writer);
                 Some(scatterer)
             }
             None => None,
@@ -56,28 +105,42 @@ impl Server {
 
         let stderr_scatterer = match container_stderr {
             Some(stream) => {
-                let mut scatterer = io::Scatterer::stderr(stream);
-                scatterer.add_sink(Rc::new(RefCell::new(Writer::stderr(logger.clone()))));
+                let writer = Rc::new(RefCell::new(Writer::stderr(logger.clone())));
+                let mut scatterer = io::Scatterer::stderr(stream, attach_replay_size);
+                scatterer.add_sink(writer.clone());
+                log_writers.push(writer);
                 Some(scatterer)
             }
             None => None,
         };
 
-        Self {
+        Ok(Self {
             reactor: Reactor::new(
                 Duration::from_millis(5000),
                 stdin_gatherer,
                 stdin_once,
+                stdin_exclusive,
                 stdout_scatterer,
                 stderr_scatterer,
+                logger,
+                log_writers,
                 signal::Handler::new(sigfd, container_pid),
                 attach_listener,
+                attach_config,
+                attach_access,
+                control_listener,
+                container_console,
             ),
-        }
+        })
     }
 
-    pub fn run(&mut self) -> TerminationStatus {
+    pub fn run(&mut self) -> ExitStatus {
         debug!("[shim] serving container");
         self.reactor.run()
     }
+
+    // Called once the exit status is saved.
+    pub fn finish(&mut self, exit_status: &ExitStatus) {
+        self.reactor.finish(exit_status)
+    }
 }
@@ -2,12 +2,20 @@ use std::io;
 
 use log::{debug, warn};
 use mio::{Evented, Poll, PollOpt, Ready, Token};
-use nix::sys::signal::{Signal, Signal::SIGCHLD};
+use nix::sys::signal::{
+    Signal,
+    Signal::{SIGCHLD, SIGUSR1},
+};
 use nix::unistd::Pid;
 
 use crate::nixtools::process::{get_child_termination_status, kill, KillResult, TerminationStatus};
 use crate::nixtools::signal::Signalfd;
 
+// Signals the shim acts upon itself instead of forwarding them to the container.
+pub enum Action {
+    ReopenLogs,
+}
+
 pub struct Handler {
     sigfd: Signalfd,
     container_pid: Pid,
@@ -23,15 +31,21 @@ impl Handler {
         }
     }
 
+    pub fn container_pid(&self) -> Pid {
+        self.container_pid
+    }
+
     pub fn container_status(&self) -> Option<TerminationStatus> {
         self.container_status
     }
 
-    pub fn handle_signal(&mut self) {
+    pub fn handle_signal(&mut self) -> Option<Action> {
         match self.sigfd.read_signal() {
             SIGCHLD => self.handle_sigchld(),
+            SIGUSR1 => return Some(Action::ReopenLogs),
             signal => forward_signal(self.container_pid, signal),
         }
+        None
     }
 
     fn handle_sigchld(&mut self) {
@@ -1,31 +1,39 @@
+use std::env;
 use std::ffi::CString;
 use std::fs;
-use std::io::Read;
+use std::io::{self, Read};
 use std::panic;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
 
-use chrono::Utc;
 use log::{debug, error, info, warn};
 use nix::sys::signal::{
     Signal,
-    Signal::{SIGCHLD, SIGINT, SIGKILL, SIGQUIT, SIGTERM},
+    Signal::{SIGCHLD, SIGINT, SIGKILL, SIGQUIT, SIGTERM, SIGUSR1},
 };
 use nix::unistd::{execv, fork, ForkResult, Pid};
 use structopt::StructOpt;
 use syslog::{BasicLogger, Facility, Formatter3164};
 
+use shimmy::container::attach::{
+    Access as AttachAccess, Config as AttachConfig, DetachKeys, OverflowPolicy,
+};
+use shimmy::container::exit::ExitStatus;
+use shimmy::container::logger::{
+    fluentd::{Address as FluentdAddress, Config as FluentdConfig},
+    journald::Config as JournaldConfig,
+    syslog_sink::{Address as SyslogAddress, Config as SyslogConfig, Protocol as SyslogProtocol},
+    Compression as LogCompression, Config as LogConfig, Format as LogFormat,
+};
 use shimmy::container::server::Server as ContainerServer;
+use shimmy::nixtools::console::{Console, ConsoleSocket};
 use shimmy::nixtools::misc::{
     _exit, session_start, set_child_subreaper, set_parent_death_signal, to_pipe_fd,
 };
-use shimmy::nixtools::process::{
-    kill, KillResult, TerminationStatus as ProcessTerminationStatus,
-    TerminationStatus::{Exited, Signaled},
-};
+use shimmy::nixtools::process::{kill, KillResult, TerminationStatus::Exited};
 use shimmy::nixtools::signal::{signals_block, signals_restore, Signalfd};
-use shimmy::nixtools::stdio::{create_pipes, set_stdio};
+use shimmy::nixtools::stdio::{create_pipes, set_stdio, PtyMaster};
 use shimmy::runtime::{await_runtime_termination, TerminationStatus as RuntimeTerminationStatus};
 use shimmy::syncpipe::SyncPipe;
 
@@ -58,8 +66,84 @@ struct CliOpt {
     #[structopt(long = "container-pidfile", parse(from_os_str))]
     container_pidfile: PathBuf,
 
+    /// container log file, omit to log to journald, syslog or fluentd only
     #[structopt(long = "container-logfile", parse(from_os_str))]
-    container_logfile: PathBuf,
+    container_logfile: Option<PathBuf>,
+
+    /// separate log file for container STDOUT (instead of --container-logfile)
+    #[structopt(long = "container-stdout-logfile", parse(from_os_str))]
+    container_stdout_logfile: Option<PathBuf>,
+
+    /// separate log file for container STDERR (instead of --container-logfile)
+    #[structopt(long = "container-stderr-logfile", parse(from_os_str))]
+    container_stderr_logfile: Option<PathBuf>,
+
+    /// container log format: text, cri (kubelet's), json-file (Docker's), or raw (binary-safe records)
+    #[structopt(long = "log-format", default_value = "text")]
+    log_format: LogFormat,
+
+    /// max log line size (bytes), longer lines are split (marked partial in cri format), 0 - no limit [default: 16384 for cri, 0 otherwise]
+    #[structopt(long = "log-max-line-size")]
+    log_max_line_size: Option<usize>,
+
+    /// rotate the container log file once it's over this size (bytes), 0 - never
+    #[structopt(long = "log-max-size", default_value = "0")]
+    log_max_size: u64,
+
+    /// max number of container log files kept on rotation, including the current one
+    #[structopt(long = "log-max-files", default_value = "1")]
+    log_max_files: usize,
+
+    /// compress rotated container log files: gzip or zstd
+    #[structopt(long = "log-compress")]
+    log_compress: Option<LogCompression>,
+
+    /// also send container logs to the systemd journal
+    #[structopt(long = "log-journald")]
+    log_journald: bool,
+
+    /// journal native protocol socket path
+    #[structopt(
+        long = "log-journald-socket",
+        default_value = "/run/systemd/journal/socket",
+        parse(from_os_str)
+    )]
+    log_journald_socket: PathBuf,
+
+    /// also send container logs to syslog: unix:///dev/log, udp://host[:port] or tcp://host[:port]
+    #[structopt(long = "log-syslog-address")]
+    log_syslog_address: Option<SyslogAddress>,
+
+    /// syslog message format: rfc3164 or rfc5424
+    #[structopt(long = "log-syslog-format", default_value = "rfc3164")]
+    log_syslog_format: SyslogProtocol,
+
+    #[structopt(
+        long = "log-syslog-facility",
+        default_value = "daemon",
+        parse(try_from_str = parse_syslog_facility)
+    )]
+    log_syslog_facility: Facility,
+
+    /// syslog tag, {id}, {short-id} and {name} are replaced with the container's ones
+    #[structopt(long = "log-syslog-tag", default_value = "{short-id}")]
+    log_syslog_tag: String,
+
+    /// also send container logs to fluentd (forward protocol): tcp://host[:port] or unix:///path
+    #[structopt(long = "log-fluentd-address")]
+    log_fluentd_address: Option<FluentdAddress>,
+
+    /// fluentd tag, {id}, {short-id}, {name} and {stream} are replaced with the container's ones
+    #[structopt(long = "log-fluentd-tag", default_value = "shimmy.{short-id}.{stream}")]
+    log_fluentd_tag: String,
+
+    /// max size (bytes) of logs buffered while fluentd is unavailable
+    #[structopt(long = "log-fluentd-buffer-size", default_value = "8388608")]
+    log_fluentd_buffer_size: usize,
+
+    /// human-readable container name to tag logs with
+    #[structopt(long = "container-name")]
+    container_name: Option<String>,
 
     #[structopt(long = "container-exitfile", parse(from_os_str))]
     container_exitfile: PathBuf,
@@ -67,11 +151,63 @@ struct CliOpt {
     #[structopt(long = "container-attachfile", parse(from_os_str))]
     container_attachfile: PathBuf,
 
+    /// control socket path (JSON-lines commands: status, kill, close-stdin, reopen-logs, wait)
+    #[structopt(long = "container-controlfile", parse(from_os_str))]
+    container_controlfile: Option<PathBuf>,
+
     #[structopt(long = "stdin")]
     stdin: bool,
 
     #[structopt(long = "stdin-once")]
     stdin_once: bool,
+
+    /// only one attach client at a time writes to container's STDIN
+    #[structopt(long = "stdin-exclusive")]
+    stdin_exclusive: bool,
+
+    /// key sequence detaching an attach client without closing STDIN, e.g. ctrl-p,ctrl-q
+    #[structopt(long = "detach-keys")]
+    detach_keys: Option<DetachKeys>,
+
+    /// size of container's output history kept per stream for late attach clients (bytes)
+    #[structopt(long = "attach-replay-size", default_value = "65536")]
+    attach_replay_size: usize,
+
+    /// max size of output queued for a slow attach client (bytes)
+    #[structopt(long = "attach-queue-size", default_value = "1048576")]
+    attach_queue_size: usize,
+
+    /// what to do with a slow attach client: drop-oldest (output) or disconnect
+    #[structopt(long = "attach-overflow-policy", default_value = "disconnect")]
+    attach_overflow_policy: OverflowPolicy,
+
+    /// attach socket file mode (octal, e.g. 0660)
+    #[structopt(long = "attach-socket-mode", parse(try_from_str = parse_file_mode))]
+    attach_socket_mode: Option<u32>,
+
+    /// attach socket file owner (uid)
+    #[structopt(long = "attach-socket-uid")]
+    attach_socket_uid: Option<u32>,
+
+    /// attach socket file group (gid)
+    #[structopt(long = "attach-socket-gid")]
+    attach_socket_gid: Option<u32>,
+
+    /// peer UIDs allowed to attach (comma-separated); anyone if neither UIDs nor GIDs are set
+    #[structopt(long = "attach-allowed-uids", use_delimiter = true)]
+    attach_allowed_uids: Vec<u32>,
+
+    /// peer (primary) GIDs allowed to attach (comma-separated)
+    #[structopt(long = "attach-allowed-gids", use_delimiter = true)]
+    attach_allowed_gids: Vec<u32>,
+
+    /// allocate PTY for the container (requires terminal: true in config.json)
+    #[structopt(long = "terminal")]
+    terminal: bool,
+
+    /// remove sockets and pidfiles once the container exit status is saved
+    #[structopt(long = "cleanup-on-exit")]
+    cleanup_on_exit: bool,
 }
 
 fn main() {
@@ -98,8 +234,25 @@ fn main() {
     session_start();
     set_child_subreaper();
 
-    let oldmask = signals_block(&[SIGCHLD, SIGINT, SIGQUIT, SIGTERM]);
-    let (iomaster, ioslave) = create_pipes(opt.stdin, true, true);
+    // SIGUSR1 makes the shim reopen container logs (e.g. after logrotate).
+    let oldmask = signals_block(&[SIGCHLD, SIGINT, SIGQUIT, SIGTERM, SIGUSR1]);
+
+    // In terminal mode the container gets the PTY slave as its STDIO,
+    // but runtime's STDERR is still needed to report its failures.
+    let console_socket = if opt.terminal {
+        match ConsoleSocket::new(env::temp_dir()) {
+            Ok(console_socket) => Some(console_socket),
+            Err(err) => {
+                let err = format!("creation of console socket failed: {}", err);
+                error!("[shim] {}", err);
+                SyncPipe::new(to_pipe_fd(opt.syncpipe_fd)).report_shim_failure(&err);
+                exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let (iomaster, ioslave) = create_pipes(opt.stdin && !opt.terminal, !opt.terminal, true);
 
     let runtime_pid = match unsafe { fork() } {
         Ok(ForkResult::Parent { child }) => child,
@@ -120,6 +273,7 @@ fn main() {
                 container_id: opt.container_id,
                 pidfile: opt.container_pidfile,
                 bundle: opt.bundle,
+                console_socket: console_socket.as_ref().map(|s| s.path().to_path_buf()),
             });
             _exit(127);
         }
@@ -129,24 +283,120 @@ fn main() {
     // Shim process (cont.)
     drop(ioslave);
 
-    let mut sigfd = Signalfd::new(&[SIGCHLD, SIGINT, SIGQUIT, SIGTERM]);
+    let mut sigfd = Signalfd::new(&[SIGCHLD, SIGINT, SIGQUIT, SIGTERM, SIGUSR1]);
     match await_runtime_termination(&mut sigfd, runtime_pid) {
         RuntimeTerminationStatus::Solitary(Exited(.., 0), inflight) => {
             debug!("[shim] runtime terminated normally");
 
-            let container_pid = read_container_pidfile(opt.container_pidfile);
+            let container_pid = read_container_pidfile(&opt.container_pidfile);
+
+            let (container_streams, container_console) = match console_socket {
+                Some(console_socket) => {
+                    let pty_master = match console_socket.receive_pty_master() {
+                        Ok(pty_master) => pty_master,
+                        Err(err) => {
+                            let err = format!("receiving PTY master failed: {}", err);
+                            error!("[shim] {}", err);
+                            SyncPipe::new(to_pipe_fd(opt.syncpipe_fd)).report_shim_failure(&err);
+                            kill_unserved_container(container_pid);
+                            // exit() skips the removal of its private directory.
+                            drop(console_socket);
+                            exit(1);
+                        }
+                    };
+                    let console = Console::new(pty_master);
+                    (
+                        PtyMaster::new(pty_master, opt.stdin).streams(),
+                        Some(console),
+                    )
+                }
+                None => (iomaster.streams(), None),
+            };
 
             // Make sure we are ready to serve container
             // before reporting so back to the manager
             // (i.e. attach socket is ready, logger is ready, etc).
-            let mut container_server = ContainerServer::new(
+            let container_server = ContainerServer::new(
                 container_pid,
-                opt.container_attachfile,
-                opt.container_logfile,
-                iomaster.streams(),
+                &opt.container_attachfile,
+                opt.container_controlfile.as_ref(),
+                (
+                    opt.container_stdout_logfile
+                        .as_ref()
+                        .or(opt.container_logfile.as_ref()),
+                    opt.container_stderr_logfile
+                        .as_ref()
+                        .or(opt.container_logfile.as_ref()),
+                ),
+                LogConfig {
+                    format: opt.log_format,
+                    max_line_size: opt
+                        .log_max_line_size
+                        .unwrap_or_else(|| opt.log_format.default_max_line_size()),
+                    max_size: opt.log_max_size,
+                    max_files: opt.log_max_files,
+                    compression: opt.log_compress,
+                    journald: if opt.log_journald {
+                        Some(JournaldConfig {
+                            socket_path: opt.log_journald_socket,
+                            container_id: opt.container_id.clone(),
+                            container_name: opt.container_name.clone(),
+                        })
+                    } else {
+                        None
+                    },
+                    syslog: match opt.log_syslog_address {
+                        Some(address) => Some(SyslogConfig {
+                            address,
+                            protocol: opt.log_syslog_format,
+                            facility: opt.log_syslog_facility,
+                            tag: opt.log_syslog_tag,
+                            container_id: opt.container_id.clone(),
+                            container_name: opt.container_name.clone(),
+                        }),
+                        None => None,
+                    },
+                    fluentd: match opt.log_fluentd_address {
+                        Some(address) => Some(FluentdConfig {
+                            address,
+                            tag: opt.log_fluentd_tag,
+                            buffer_size: opt.log_fluentd_buffer_size,
+                            container_id: opt.container_id,
+                            container_name: opt.container_name,
+                        }),
+                        None => None,
+                    },
+                },
+                container_streams,
+                container_console,
                 opt.stdin_once,
+                opt.stdin_exclusive,
+                opt.attach_replay_size,
+                AttachConfig {
+                    queue_size: opt.attach_queue_size,
+                    overflow_policy: opt.attach_overflow_policy,
+                    raw_resize: opt.terminal,
+                    detach_keys: opt.detach_keys,
+                },
+                AttachAccess {
+                    socket_mode: opt.attach_socket_mode,
+                    socket_uid: opt.attach_socket_uid,
+                    socket_gid: opt.attach_socket_gid,
+                    allowed_uids: opt.attach_allowed_uids,
+                    allowed_gids: opt.attach_allowed_gids,
+                },
                 sigfd,
             );
+            let mut container_server = match container_server {
+                Ok(container_server) => container_server,
+                Err(err) => {
+                    // Nobody would serve the container, so it's not worth keeping.
+                    error!("[shim] failed to start serving container: {}", err);
+                    SyncPipe::new(to_pipe_fd(opt.syncpipe_fd)).report_shim_failure(&err);
+                    kill_unserved_container(container_pid);
+                    exit(1);
+                }
+            };
 
             SyncPipe::new(to_pipe_fd(opt.syncpipe_fd)).report_container_pid(container_pid);
 
@@ -154,7 +404,19 @@ fn main() {
                 deliver_inflight_signal(container_pid, sig);
             }
 
-            save_container_termination_status(opt.container_exitfile, container_server.run());
+            let exit_status = container_server.run();
+            save_container_termination_status(&opt.container_exitfile, &exit_status);
+            container_server.finish(&exit_status);
+
+            if opt.cleanup_on_exit {
+                let mut artifacts = vec![
+                    &opt.container_attachfile,
+                    &opt.container_pidfile,
+                    &opt.pidfile,
+                ];
+                artifacts.extend(opt.container_controlfile.as_ref());
+                remove_shim_artifacts(&artifacts);
+            }
         }
 
         ts => {
@@ -183,28 +445,22 @@ fn deliver_inflight_signal(container_pid: Pid, signal: Signal) {
     }
 }
 
-fn save_container_termination_status<P: AsRef<Path>>(
-    filename: P,
-    status: ProcessTerminationStatus,
-) {
+fn kill_unserved_container(container_pid: Pid) {
+    match kill(container_pid, SIGKILL) {
+        Ok(KillResult::Delivered) => (),
+        Ok(KillResult::ProcessNotFound) => warn!("Failed to kill container, probably exited"),
+        Err(err) => warn!("Failed to kill container: {}", err),
+    }
+}
+
+fn save_container_termination_status<P: AsRef<Path>>(filename: P, status: &ExitStatus) {
     debug!(
         "[shim] saving container termination status [{}] to {}",
         status,
         filename.as_ref().display()
     );
 
-    let now = Utc::now().to_rfc3339();
-    let message = match status {
-        Exited(.., code) => format!(
-            r#"{{"at": "{}", "reason": "exited", "exitCode": {}}}"#,
-            now, code,
-        ),
-        Signaled(.., sig) => format!(
-            r#"{{"at": "{}", "reason": "signaled", "signal": {}}}"#,
-            now, sig as libc::c_int,
-        ),
-    };
-    if let Err(err) = fs::write(&filename, message) {
+    if let Err(err) = fs::write(&filename, status.to_json()) {
         panic!(
             "write() to container exit file {} failed: {}",
             filename.as_ref().display(),
@@ -213,6 +469,28 @@ fn save_container_termination_status<P: AsRef<Path>>(
     }
 }
 
+fn remove_shim_artifacts<P: AsRef<Path>>(filenames: &[P]) {
+    for filename in filenames {
+        match fs::remove_file(filename) {
+            Ok(_) => debug!("[shim] removed {}", filename.as_ref().display()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => warn!(
+                "[shim] failed to remove {}: {}",
+                filename.as_ref().display(),
+                err
+            ),
+        }
+    }
+}
+
+fn parse_file_mode(mode: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(mode, 8)
+}
+
+fn parse_syslog_facility(facility: &str) -> Result<Facility, String> {
+    Facility::from_str(facility).map_err(|_| format!("unknown syslog facility {}", facility))
+}
+
 fn read_container_pidfile<P: AsRef<Path>>(filename: P) -> Pid {
     let content = fs::read_to_string(&filename).expect("fs::read_to_string() failed");
     return Pid::from_raw(
@@ -240,6 +518,7 @@ struct RuntimeCommand {
     container_id: String,
     pidfile: PathBuf,
     bundle: PathBuf,
+    console_socket: Option<PathBuf>,
 }
 
 impl RuntimeCommand {
@@ -256,6 +535,10 @@ impl RuntimeCommand {
         argv.push(CString::new(self.bundle.to_str().unwrap()).unwrap());
         argv.push(CString::new("--pid-file").unwrap());
         argv.push(CString::new(self.pidfile.to_str().unwrap()).unwrap());
+        if let Some(console_socket) = &self.console_socket {
+            argv.push(CString::new("--console-socket").unwrap());
+            argv.push(CString::new(console_socket.to_str().unwrap()).unwrap());
+        }
         argv.push(CString::new(self.container_id.as_str()).unwrap());
 
         return argv;
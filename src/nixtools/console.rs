@@ -0,0 +1,155 @@
+use std::fs::{self, DirBuilder};
+use std::io::{self, IoSliceMut};
+use std::os::unix::fs::DirBuilderExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{debug, error};
+use nix::cmsg_space;
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, UnixAddr};
+use nix::sys::termios::{tcgetattr, LocalFlags, SpecialCharacterIndices};
+use nix::unistd::{close, getpid, write};
+
+use super::socket::bind_unix_listener;
+
+// runc sends the PTY name along with the master fd.
+const PTY_NAME_BUF_SIZE: usize = 4096;
+
+pub struct ConsoleSocket {
+    dir: PathBuf,
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl ConsoleSocket {
+    // The socket is placed in a fresh directory accessible only by the shim,
+    // so nobody else can connect to it or put anything in its place.
+    pub fn new<P: AsRef<Path>>(parent_dir: P) -> io::Result<Self> {
+        let dir = create_private_dir(parent_dir.as_ref())?;
+        let path = dir.join("console.sock");
+        let listener = match bind_unix_listener(&path) {
+            Ok(listener) => listener,
+            Err(err) => {
+                let _ = fs::remove_dir(&dir);
+                return Err(err);
+            }
+        };
+
+        // The runtime connects and sends the PTY master before it exits,
+        // hence by the time we accept() the connection must be already queued.
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            dir,
+            path,
+            listener,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn receive_pty_master(&self) -> io::Result<RawFd> {
+        let (stream, _) = self.listener.accept()?;
+
+        let mut buf = [0; PTY_NAME_BUF_SIZE];
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let mut cmsgspace = cmsg_space!([RawFd; 1]);
+        let msg = recvmsg::<UnixAddr>(
+            stream.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsgspace),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+
+        for cmsg in msg.cmsgs() {
+            if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                if let Some(fd) = fds.first() {
+                    debug!("[shim] received PTY master fd {}", fd);
+                    return Ok(*fd);
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no PTY master received over console socket",
+        ))
+    }
+}
+
+impl Drop for ConsoleSocket {
+    fn drop(&mut self) {
+        for res in [fs::remove_file(&self.path), fs::remove_dir(&self.dir)] {
+            if let Err(err) = res {
+                error!(
+                    "console socket {} removal failed: {}",
+                    self.path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+fn create_private_dir(parent_dir: &Path) -> io::Result<PathBuf> {
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let mut attempt = 0;
+    loop {
+        let dir = parent_dir.join(format!("shimmy-{}-{:x}", getpid(), seed + attempt));
+        match DirBuilder::new().mode(0o700).create(&dir) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists && attempt < 100 => attempt += 1,
+            res => return res.map(|_| dir),
+        }
+    }
+}
+
+nix::ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, libc::winsize);
+
+pub struct Console(RawFd);
+
+impl Console {
+    pub fn new(pty_master: RawFd) -> Self {
+        Self(
+            fcntl(pty_master, FcntlArg::F_DUPFD_CLOEXEC(0))
+                .expect("dup(PTY master) for console failed"),
+        )
+    }
+
+    pub fn resize(&self, rows: u16, cols: u16) -> nix::Result<()> {
+        let ws = libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+        unsafe { tiocswinsz(self.0, &ws) }.map(drop)
+    }
+
+    // Closing the PTY master doesn't end the container's input, the line
+    // discipline does it on the VEOF character (^D) in the canonical mode.
+    // Just like a typed ^D, it only passes an unterminated line on if any.
+    pub fn send_eof(&self) -> io::Result<()> {
+        let termios = tcgetattr(self.0)?;
+        if !termios.local_flags.contains(LocalFlags::ICANON) {
+            return Err(io::Error::other("terminal is not in canonical mode"));
+        }
+        let veof = termios.control_chars[SpecialCharacterIndices::VEOF as usize];
+        write(self.0, &[veof])?;
+        Ok(())
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        if let Err(err) = close(self.0) {
+            error!("console close({}) failed: {}", self.0, err);
+        }
+    }
+}
@@ -1,5 +1,7 @@
+pub mod console;
 pub mod misc;
 pub mod pipe;
 pub mod process;
 pub mod signal;
+pub mod socket;
 pub mod stdio;
@@ -0,0 +1,85 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use log::{error, warn};
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::{umask, Mode};
+use nix::unistd::close;
+
+// sun_path is 108 bytes long on Linux, including the terminating NUL.
+const SUN_PATH_MAX_LEN: usize = 107;
+
+// A socket file left behind by a crashed predecessor is replaced,
+// but only if nobody is listening on it anymore.
+pub fn bind_unix_listener<P: AsRef<Path>>(path: P) -> io::Result<UnixListener> {
+    with_short_path(path.as_ref(), |path| match UnixListener::bind(path) {
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse && is_stale(path) => {
+            warn!("removing stale socket {}", path.display());
+            fs::remove_file(path)?;
+            UnixListener::bind(path)
+        }
+        res => res,
+    })
+}
+
+// Binds with the given umask in effect, so that the socket is never accessible
+// more broadly than that; returns the process umask restored afterwards.
+pub fn bind_unix_listener_masked<P: AsRef<Path>>(
+    path: P,
+    mask: Mode,
+) -> io::Result<(UnixListener, Mode)> {
+    let prev = umask(mask);
+    let res = bind_unix_listener(path);
+    umask(prev);
+    res.map(|listener| (listener, prev))
+}
+
+fn is_stale(path: &Path) -> bool {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => matches!(
+            UnixStream::connect(path),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused
+        ),
+        _ => false,
+    }
+}
+
+// Paths too long for sun_path are accessed through a short
+// /proc/self/fd/<dirfd>/<name> alias of the parent directory (as conmon does).
+fn with_short_path<T, F>(path: &Path, f: F) -> io::Result<T>
+where
+    F: FnOnce(&Path) -> io::Result<T>,
+{
+    if path.as_os_str().len() <= SUN_PATH_MAX_LEN {
+        return f(path);
+    }
+
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "socket path has no file name",
+            ))
+        }
+    };
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+
+    let dirfd = open(
+        dir,
+        OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+        Mode::empty(),
+    )?;
+    let res = f(&Path::new(&format!("/proc/self/fd/{}", dirfd)).join(name));
+    if let Err(err) = close(dirfd) {
+        error!("close({}) of socket dir failed: {}", dirfd, err);
+    }
+    res
+}
@@ -4,7 +4,7 @@ use std::mem;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use log::error;
-use nix::fcntl::{open, OFlag};
+use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
 use nix::sys::stat::Mode;
 use nix::unistd::{close, dup2};
 
@@ -58,6 +58,12 @@ impl Write for OStream {
     }
 }
 
+impl AsRawFd for OStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
 pub fn set_stdio((ins, outs, errs): (Option<IStream>, Option<OStream>, Option<OStream>)) {
     match ins {
         Some(IStream(fd)) => {
@@ -132,6 +138,32 @@ impl PipeSlave {
     }
 }
 
+pub struct PtyMaster {
+    ins: Option<OStream>,
+    outs: Option<IStream>,
+}
+
+impl PtyMaster {
+    pub fn new(fd: RawFd, use_stdin: bool) -> Self {
+        let ins = if use_stdin {
+            let dupfd = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(0)).expect("dup(PTY master) failed");
+            Some(OStream(dupfd))
+        } else {
+            None
+        };
+
+        Self {
+            ins,
+            outs: Some(IStream(fd)),
+        }
+    }
+
+    // PTY merges container's STDOUT and STDERR, hence no separate STDERR stream.
+    pub fn streams(self) -> (Option<OStream>, Option<IStream>, Option<IStream>) {
+        (self.ins, self.outs, None)
+    }
+}
+
 pub fn create_pipes(
     use_stdin: bool,
     use_stdout: bool,
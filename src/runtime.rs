@@ -3,7 +3,7 @@ use std::fmt;
 use log::debug;
 use nix::sys::signal::{
     Signal,
-    Signal::{SIGCHLD, SIGINT, SIGQUIT, SIGTERM},
+    Signal::{SIGCHLD, SIGINT, SIGQUIT, SIGTERM, SIGUSR1},
 };
 use nix::unistd::Pid;
 
@@ -101,6 +101,7 @@ pub fn await_runtime_termination(sigfd: &mut Signalfd, runtime_pid: Pid) -> Term
                     Err(err) => panic!("kill(runtime_pid, {}) failed: {:?}", sig, err),
                 }
             }
+            SIGUSR1 => debug!("[shim] SIGUSR1 received, container logs aren't open yet"),
             sig => panic!("unexpected signal received {:?}", sig),
         };
     }
@@ -39,6 +39,21 @@ impl MessageContainerPid {
     }
 }
 
+#[derive(Serialize)]
+struct MessageShimFailure<'a> {
+    kind: &'static str,
+    message: &'a str,
+}
+
+impl<'a> MessageShimFailure<'a> {
+    fn new(message: &'a str) -> Self {
+        MessageShimFailure {
+            kind: "shim_failure",
+            message,
+        }
+    }
+}
+
 pub struct SyncPipe(File);
 
 impl SyncPipe {
@@ -61,4 +76,10 @@ impl SyncPipe {
             .expect("JSON serialization failed");
         self.0.write_all(&msg).expect("SyncPipe.write() failed");
     }
+
+    pub fn report_shim_failure(&mut self, message: &str) {
+        let msg = serde_json::to_vec(&MessageShimFailure::new(message))
+            .expect("JSON serialization failed");
+        self.0.write_all(&msg).expect("SyncPipe.write() failed");
+    }
 }
@@ -0,0 +1,69 @@
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::exit;
+
+use structopt::StructOpt;
+
+use shimmy::container::logger::reader::{RawRecords, Reader};
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "shimmy-logs",
+    about = "prints a container log including its rotated (and compressed) files"
+)]
+struct CliOpt {
+    /// list the log files instead of printing their content
+    #[structopt(long = "list")]
+    list: bool,
+
+    /// decode a log written with --log-format raw, printing the output exactly as produced
+    #[structopt(long = "raw")]
+    raw: bool,
+
+    /// with --raw, print only this stream (stdout or stderr)
+    #[structopt(long = "stream", requires = "raw")]
+    stream: Option<String>,
+
+    /// container log file path (as given to shimmy --container-logfile)
+    #[structopt(parse(from_os_str))]
+    logfile: PathBuf,
+}
+
+fn main() {
+    let opt = CliOpt::from_args();
+
+    let mut reader = match Reader::open(&opt.logfile) {
+        Ok(reader) => reader,
+        Err(err) => {
+            eprintln!("failed to open {}: {}", opt.logfile.display(), err);
+            exit(1);
+        }
+    };
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let res = if opt.list {
+        reader
+            .segments()
+            .try_for_each(|segment| writeln!(stdout, "{}", segment.display()))
+    } else if opt.raw {
+        RawRecords::new(reader).try_for_each(|record| {
+            let record = record?;
+            match opt.stream.as_deref() {
+                Some(stream) if stream != record.stream => Ok(()),
+                _ => stdout.write_all(&record.payload),
+            }
+        })
+    } else {
+        io::copy(&mut reader, &mut stdout).map(|_| ())
+    };
+
+    match res {
+        Ok(_) => (),
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => (),
+        Err(err) => {
+            eprintln!("failed to read {}: {}", opt.logfile.display(), err);
+            exit(1);
+        }
+    }
+}